@@ -22,6 +22,18 @@ fn no_preempt_end(result: local_impl::Result) {
   local_impl::no_preempt_end(result)
 }
 
+// Keeps the running thread from being preempted until the matching
+// `preempt_enable`. Unlike `no_preempt` these only count, so the two may be
+// called in different contexts on the same CPU, e.g. when a spin lock a
+// thread took on its way to park is let go by the scheduler.
+pub fn preempt_disable() {
+  local_impl::preempt_disable()
+}
+
+pub fn preempt_enable() {
+  local_impl::preempt_enable()
+}
+
 
 pub struct Arch<T> {
   p: ::core::marker::PhantomData<T>
@@ -42,48 +54,153 @@ impl<T> Arch<T> {
     local_impl::set(value as *const T as usize)
   }
 
+  // Whether the CPU is running a thread, as opposed to the scheduler or a
+  // thread on its way in or out of a switch.
+  pub fn in_thread() -> bool {
+    local_impl::in_thread()
+  }
+
+  pub unsafe fn set_in_thread(value: bool) {
+    local_impl::set_in_thread(value)
+  }
+
+  // Whether the running code is inside a `no_preempt` section, or between
+  // `preempt_disable` and `preempt_enable`.
+  pub fn preempt_disabled() -> bool {
+    local_impl::preempt_disabled()
+  }
+
+  // Called first thing on a new thread: a thread starts out preemptible
+  // whatever state the scheduler switched to it in.
+  pub unsafe fn thread_start() {
+    local_impl::thread_start();
+    local_impl::set_in_thread(true);
+  }
+
+  // Counts a timer tick against the running thread, returning how many it
+  // has used since it was last switched to.
+  pub fn tick() -> usize {
+    local_impl::tick()
+  }
+
+  pub fn reset_ticks() {
+    local_impl::reset_ticks()
+  }
+
+  // Whether the running thread is unwinding from a panic. Switched along
+  // with the thread, since other threads may run while it unwinds.
+  pub fn panicking() -> bool {
+    local_impl::panicking()
+  }
+
+  pub unsafe fn set_panicking(value: bool) {
+    local_impl::set_panicking(value)
+  }
+
 }
 
 #[cfg(feature = "hosted")]
 mod local_impl {
 
-  use std::cell::RefCell;
+  use std::cell::Cell;
 
   pub type Result = bool;
 
+  // Cells rather than RefCells, since the simulated timer interrupt (a
+  // signal handler) may read them while the thread is in the middle of
+  // changing them.
   thread_local! {
-    pub static LOCAL: RefCell<usize> = RefCell::new(0);
-    pub static NO_PREEMPT: RefCell<Result> = RefCell::new(false);
+    pub static LOCAL: Cell<usize> = Cell::new(0);
+    pub static NO_PREEMPT: Cell<Result> = Cell::new(false);
+    pub static IN_THREAD: Cell<bool> = Cell::new(false);
+    pub static TICKS: Cell<usize> = Cell::new(0);
+    pub static DISABLED: Cell<usize> = Cell::new(0);
+    pub static PANICKING: Cell<bool> = Cell::new(false);
   }
   
   pub fn get() -> usize {
-    let mut ret = 0;
-    LOCAL.with(|l| ret = *l.borrow());
-    ret
+    LOCAL.with(|l| l.get())
   }
   
   pub fn set(value: usize) {
-    LOCAL.with(|l| *l.borrow_mut() = value);
+    LOCAL.with(|l| l.set(value));
   }
 
   pub fn no_preempt_start() -> Result {
-    // TODO: should be atomic bool...
-    let mut old = false;
-    NO_PREEMPT.with(|l| { old = *l.borrow(); *l.borrow_mut() = true });
-    old
+    NO_PREEMPT.with(|l| { let old = l.get(); l.set(true); old })
   }
 
   pub fn no_preempt_end(old: Result) {
-    NO_PREEMPT.with(|l| { *l.borrow_mut() = old });
+    NO_PREEMPT.with(|l| l.set(old));
+  }
+
+  pub fn preempt_disable() {
+    DISABLED.with(|d| d.set(d.get() + 1));
+  }
+
+  pub fn preempt_enable() {
+    DISABLED.with(|d| d.set(d.get() - 1));
+  }
+
+  pub fn preempt_disabled() -> bool {
+    NO_PREEMPT.with(|l| l.get()) || DISABLED.with(|d| d.get() > 0)
+  }
+
+  pub fn thread_start() {
+    no_preempt_end(false);
+  }
+
+  pub fn in_thread() -> bool {
+    IN_THREAD.with(|t| t.get())
+  }
+
+  pub fn set_in_thread(value: bool) {
+    IN_THREAD.with(|t| t.set(value));
+  }
+
+  pub fn tick() -> usize {
+    TICKS.with(|t| { t.set(t.get() + 1); t.get() })
+  }
+
+  pub fn reset_ticks() {
+    TICKS.with(|t| t.set(0));
+  }
+
+  // The panic hook sets the flag, but nothing clears it when user code
+  // catches the panic. So it only counts while the OS thread is unwinding
+  // too: once it isn't, the panic that set it was caught. (While another
+  // barn thread on the OS thread is halfway through unwinding, a caught
+  // panic still counts until that one is done.)
+  pub fn panicking() -> bool {
+    PANICKING.with(|p| {
+      if p.get() && !::std::thread::panicking() {
+        p.set(false);
+      }
+      p.get()
+    })
+  }
+
+  pub fn set_panicking(value: bool) {
+    PANICKING.with(|p| p.set(value));
   }
 
 }
 
+// The current thread lives in a debug register, so each CPU has its own, but
+// the flags and counts below are shared: only one CPU may run threads, which
+// is why `Scheduler::new_smp` is only there when hosted.
 #[cfg(all(not(feature = "hosted"), target_arch = "x86"))]
 mod local_impl {
 
+  use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+
   pub type Result = bool;
 
+  static IN_THREAD: AtomicBool = ATOMIC_BOOL_INIT;
+  static TICKS: AtomicUsize = ATOMIC_USIZE_INIT;
+  static NO_PREEMPT: AtomicBool = ATOMIC_BOOL_INIT;
+  static DISABLED: AtomicUsize = ATOMIC_USIZE_INIT;
+
   pub fn get() -> usize {
     let gs: u32;
     unsafe {
@@ -118,17 +235,60 @@ mod local_impl {
   }
 
 
+  // Interrupts are masked inside, so the timer only ever finds the flag set
+  // if a section was left without unmasking, e.g. across a switch.
   pub fn no_preempt_start() -> Result {
     unsafe {
       let was_enabled: bool = interrupts_enabled();
       asm!("cli" :::: "volatile");
+      NO_PREEMPT.store(true, Ordering::SeqCst);
       was_enabled
     }
   }
 
   pub fn no_preempt_end(was_enabled: Result) {
     if was_enabled {
+      NO_PREEMPT.store(false, Ordering::SeqCst);
       unsafe { asm!("sti" :::: "volatile"); }
     }
   }
+
+  pub fn preempt_disable() {
+    DISABLED.fetch_add(1, Ordering::SeqCst);
+  }
+
+  pub fn preempt_enable() {
+    DISABLED.fetch_sub(1, Ordering::SeqCst);
+  }
+
+  pub fn preempt_disabled() -> bool {
+    NO_PREEMPT.load(Ordering::SeqCst) || DISABLED.load(Ordering::SeqCst) > 0
+  }
+
+  pub fn thread_start() {
+    no_preempt_end(true);
+  }
+
+  pub fn in_thread() -> bool {
+    IN_THREAD.load(Ordering::SeqCst)
+  }
+
+  pub fn set_in_thread(value: bool) {
+    IN_THREAD.store(value, Ordering::SeqCst);
+  }
+
+  pub fn tick() -> usize {
+    TICKS.fetch_add(1, Ordering::SeqCst) + 1
+  }
+
+  pub fn reset_ticks() {
+    TICKS.store(0, Ordering::SeqCst);
+  }
+
+  // Panics aren't caught without `hosted`, so nothing ever unwinds.
+  pub fn panicking() -> bool {
+    false
+  }
+
+  pub fn set_panicking(_: bool) {}
 }
@@ -6,8 +6,11 @@ use self::alloc::boxed::Box;
 use fringe::OwnedStack;
 use scheduler;
 use lock;
-
-use core::ops::Deref;
+use thread;
+use channel;
+use irq;
+use stack;
+use time;
 
 pub struct Unit;
 impl ::scheduler::SchedulerUnit for Unit {
@@ -15,12 +18,48 @@ impl ::scheduler::SchedulerUnit for Unit {
   type N = Node;
   type Q = Queue;
   type S = OwnedStack;
+  type C = Clock;
+}
+
+// Tests get a virtual clock so that sleeping is deterministic.
+#[cfg(test)]
+pub type Clock = time::ManualClock;
+#[cfg(all(not(test), feature = "hosted"))]
+pub type Clock = time::StdClock;
+#[cfg(all(not(test), not(feature = "hosted")))]
+pub type Clock = time::TickClock;
+
+pub struct Local {
+  affinity: usize,
 }
 
-type Local = Option<()>;
+impl Default for Local {
 
-pub type Node = Box<::linked_list::Node<scheduler::Thread<Unit>>>;
-pub type Queue = ::linked_list::LinkedList<scheduler::Thread<Unit>>;
+  fn default() -> Local {
+    Local { affinity: !0 }
+  }
+
+}
+
+impl Local {
+
+  // Restricts which CPUs a balanced scheduler may move the thread to.
+  pub fn set_affinity(&mut self, mask: usize) {
+    self.affinity = mask;
+  }
+
+}
+
+impl scheduler::Affinity for Local {
+
+  fn affinity(&self) -> usize {
+    self.affinity
+  }
+
+}
+
+pub type Node = Box<scheduler::Thread<Unit>>;
+pub type Queue = scheduler::ThreadList<Unit>;
 pub type Scheduler = scheduler::Scheduler<Unit>;
 pub type Mutex<T> = lock::Mutex<T, Unit>;
 pub type MutexGuard<'a, T> = lock::MutexGuard<'a, T, Unit>;
@@ -28,52 +67,58 @@ pub type Condvar = lock::Condvar<Unit>;
 pub type RwLock<T> =  lock::RwLock<T, Unit>;
 pub type RwLockReadGuard<'a, T> = lock::RwLockReadGuard<'a, T, Unit>;
 pub type RwLockWriteGuard<'a, T> = lock::RwLockWriteGuard<'a, T, Unit>;
+pub type Semaphore = lock::Semaphore<Unit>;
+pub type SemaphoreGuard<'a> = lock::SemaphoreGuard<'a, Unit>;
+pub type Sender<T> = channel::Sender<T, Unit>;
+pub type SyncSender<T> = channel::SyncSender<T, Unit>;
+pub type Receiver<T> = channel::Receiver<T, Unit>;
+pub type OneshotSender<T> = channel::OneshotSender<T, Unit>;
+pub type OneshotReceiver<T> = channel::OneshotReceiver<T, Unit>;
+pub type IrqWaker = irq::IrqWaker<Unit>;
 pub type Thread = scheduler::Thread<Unit>;
+pub type JoinHandle<T> = thread::JoinHandle<T, Unit>;
+pub type Builder = thread::Builder<Unit>;
+pub type StackPool = stack::StackPool<OwnedStack>;
 
 
-impl scheduler::Node<Unit> for Node {
-
-  fn new(t: Thread) -> Self {
-    box ::linked_list::Node::new(t)
-  }
+/// Spawns `f` on a new thread from inside a running thread.
+pub fn spawn<F, T>(stack: OwnedStack, f: F) -> JoinHandle<T>
+  where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+  thread::spawn::<Unit, _, _>(stack, f)
+}
 
-  fn deref(&self) -> &Thread {
-    &self.value
-  }
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+  channel::channel::<T, Unit>()
+}
 
-  fn deref_mut(&mut self) -> &mut Thread {
-    &mut self.value
-  }
+pub fn sync_channel<T>(bound: usize) -> (SyncSender<T>, Receiver<T>) {
+  channel::sync_channel::<T, Unit>(bound)
+}
 
+pub fn oneshot<T>() -> (OneshotSender<T>, OneshotReceiver<T>) {
+  channel::oneshot::<T, Unit>()
 }
 
-impl ::scheduler::Queue<Unit> for Queue {
+impl scheduler::Node<Unit> for Node {
 
-  fn new() -> Queue {
-    ::linked_list::LinkedList::new()
+  fn new(t: Thread) -> Self {
+    box t
   }
 
-  fn push(&mut self, node: Node) {
-    self.push_back_node(node);
-  }
-  
-  fn pop(&mut self) -> Option<Node> {
-    self.pop_front_node()
+  fn deref(&self) -> &Thread {
+    &**self
   }
-  
-  fn front(&self) -> Option<&Node> {
-    self.list_head.as_ref()
+
+  fn deref_mut(&mut self) -> &mut Thread {
+    &mut **self
   }
 
-  fn front_mut(&mut self) -> Option<&mut Node> {
-    self.list_head.as_mut()
+  fn into_thread(self) -> Thread {
+    *self
   }
 
 }
 
-unsafe impl Send for Queue {}
-unsafe impl Sync for Queue {}
-
 
 #[cfg(test)]
 mod tests {
@@ -97,7 +142,7 @@ mod tests {
       *ran.lock().unwrap() = true;
     });
     q.push_front(t);
-    debug!("pushed: 0x{:x}", q.front().unwrap() as *const Thread as usize);
+    debug!("pushed: 0x{:x}", &**q.front().unwrap() as *const Thread as usize);
 
     let mut s: Scheduler = Scheduler::new(q);
     s.run();
@@ -192,4 +237,982 @@ mod tests {
     assert_eq!(*lock.lock().unwrap(), vec!(1, 2, 3));
   }
 
+
+  #[test]
+  fn rwlock_shared_readers() {
+    let mut q = Queue::new();
+    let lock = Arc::new(RwLock::new(0));
+    let inside = Arc::new(::std::sync::Mutex::new(0));
+    let max_inside = Arc::new(::std::sync::Mutex::new(0));
+
+    for _ in 0..3 {
+      let lock = lock.clone();
+      let inside = inside.clone();
+      let max_inside = max_inside.clone();
+      q.push_back(thread(move || {
+        let _r = lock.read().unwrap();
+        *inside.lock().unwrap() += 1;
+        {
+          let mut m = max_inside.lock().unwrap();
+          let i = *inside.lock().unwrap();
+          if i > *m { *m = i; }
+        }
+        Thread::suspend(Request::Yield);
+        *inside.lock().unwrap() -= 1;
+      }));
+    }
+
+    Scheduler::new(q).run();
+    assert_eq!(*max_inside.lock().unwrap(), 3);
+    assert!(lock.try_write().is_ok());
+  }
+
+  #[test]
+  fn rwlock_writer_preference() {
+    let mut q = Queue::new();
+    let lock = Arc::new(RwLock::new(vec!()));
+    let (l1, l2, l3) = (lock.clone(), lock.clone(), lock.clone());
+
+    // t1 reads and yields while holding, t2 queues as a writer, then t3 tries
+    // to read. t3 must wait behind t2 even though t1 is still reading.
+    let t1 = thread(move || {
+      let r = l1.read().unwrap();
+      Thread::suspend(Request::Yield);
+      assert!(l1.try_read().is_err());
+      drop(r);
+    });
+    let t2 = thread(move || {
+      l2.write().unwrap().push(2);
+    });
+    let t3 = thread(move || {
+      let r = l3.read().unwrap();
+      assert_eq!(*r, vec!(2));
+    });
+
+    q.push_back(t1);
+    q.push_back(t2);
+    q.push_back(t3);
+    Scheduler::new(q).run();
+    assert_eq!(*lock.read().unwrap(), vec!(2));
+  }
+
+  #[test]
+  fn join_smoke() {
+    let mut q = Queue::new();
+    let sum = Arc::new(::std::sync::Mutex::new(0));
+    let saved_sum = sum.clone();
+    let t = thread(move || {
+      let a = spawn(OwnedStack::new(1024 * 1024), || 1 + 1);
+      let b = spawn(OwnedStack::new(1024 * 1024), || {
+        Thread::suspend(Request::Yield);
+        40
+      });
+      *sum.lock().unwrap() = a.join() + b.join();
+    });
+    q.push_front(t);
+    Scheduler::new(q).run();
+    assert_eq!(*saved_sum.lock().unwrap(), 42);
+  }
+
+  #[test]
+  fn join_finished() {
+    let mut q = Queue::new();
+    let (child, handle) = ::thread::new::<Unit, _, _>(OwnedStack::new(1024 * 1024), || vec!(1, 2));
+    q.push_back(child);
+    Scheduler::new(q).run();
+    // The child ran to completion, so joining doesn't need to block.
+    assert_eq!(handle.join(), vec!(1, 2));
+  }
+
+  #[test]
+  fn sleep_order() {
+    use time::ManualClock;
+    use time::Clock;
+
+    let mut q = Queue::new();
+    let order = Arc::new(::std::sync::Mutex::new(vec!()));
+    let (o1, o2, o3) = (order.clone(), order.clone(), order.clone());
+    ManualClock::set(0);
+
+    q.push_back(thread(move || {
+      Thread::sleep(10);
+      o1.lock().unwrap().push((1, ManualClock::now()));
+    }));
+    q.push_back(thread(move || {
+      Thread::sleep(5);
+      o2.lock().unwrap().push((2, ManualClock::now()));
+    }));
+    q.push_back(thread(move || {
+      o3.lock().unwrap().push((3, ManualClock::now()));
+    }));
+
+    Scheduler::new(q).run();
+    assert_eq!(*order.lock().unwrap(), vec!((3, 0), (2, 5), (1, 10)));
+  }
+
+  #[test]
+  fn sleep_until_past_deadline() {
+    use time::ManualClock;
+
+    ManualClock::set(7);
+    smoke(|| {
+      Thread::sleep_until(3);
+      Thread::sleep(0);
+    });
+  }
+
+  #[test]
+  fn mutex_try_lock_for() {
+    use time::{ManualClock, Clock};
+
+    let mut q = Queue::new();
+    let lock = Arc::new(Mutex::new(()));
+    let results = Arc::new(::std::sync::Mutex::new(vec!()));
+    let (l1, l2, l3) = (lock.clone(), lock.clone(), lock.clone());
+    let (r2, r3) = (results.clone(), results.clone());
+    ManualClock::set(0);
+
+    q.push_back(thread(move || {
+      let _g = l1.lock().unwrap();
+      Thread::sleep(10);
+    }));
+    q.push_back(thread(move || {
+      let ok = l2.try_lock_for(5).is_ok();
+      r2.lock().unwrap().push((2, ok, ManualClock::now()));
+    }));
+    q.push_back(thread(move || {
+      let ok = l3.try_lock_for(20).is_ok();
+      r3.lock().unwrap().push((3, ok, ManualClock::now()));
+    }));
+
+    Scheduler::new(q).run();
+    assert_eq!(*results.lock().unwrap(), vec!((2, false, 5), (3, true, 10)));
+    assert!(lock.try_lock().is_ok());
+  }
+
+  #[test]
+  fn condvar_wait_timeout() {
+    use time::{ManualClock, Clock};
+
+    let mut q = Queue::new();
+    let pair = Arc::new((Mutex::new(false), Condvar::new()));
+    let (p1, p2, p3) = (pair.clone(), pair.clone(), pair.clone());
+    let results = Arc::new(::std::sync::Mutex::new(vec!()));
+    let (r1, r2) = (results.clone(), results.clone());
+    ManualClock::set(0);
+
+    // Nobody notifies this one.
+    q.push_back(thread(move || {
+      let &(ref lock, ref cvar) = &*p1;
+      let (_, result) = cvar.wait_timeout(lock.lock().unwrap(), 3).unwrap();
+      r1.lock().unwrap().push((1, result.timed_out(), ManualClock::now()));
+    }));
+    // This one is notified at time 8, before its timeout.
+    q.push_back(thread(move || {
+      let &(ref lock, ref cvar) = &*p2;
+      let (ready, result) = cvar.wait_timeout_while(lock.lock().unwrap(), 50, |ready| !*ready).unwrap();
+      assert!(*ready);
+      r2.lock().unwrap().push((2, result.timed_out(), ManualClock::now()));
+    }));
+    q.push_back(thread(move || {
+      Thread::sleep(8);
+      let &(ref lock, ref cvar) = &*p3;
+      *lock.lock().unwrap() = true;
+      cvar.notify_all();
+    }));
+
+    Scheduler::new(q).run();
+    assert_eq!(*results.lock().unwrap(), vec!((1, true, 3), (2, false, 8)));
+  }
+
+  #[test]
+  fn mutex_poison() {
+    let mut q = Queue::new();
+    let lock = Arc::new(Mutex::new(0));
+    let (l1, l2) = (lock.clone(), lock.clone());
+
+    q.push_back(thread(move || {
+      let _g = l1.lock().unwrap();
+      panic!("poison the lock");
+    }));
+    q.push_back(thread(move || {
+      assert!(l2.is_poisoned());
+      assert!(l2.try_lock().is_err());
+      match l2.lock() {
+        Err(poisoned) => *poisoned.into_inner() = 1,
+        Ok(_) => panic!("lock should be poisoned"),
+      }
+      l2.clear_poison();
+      *l2.lock().unwrap() += 1;
+    }));
+
+    Scheduler::new(q).run();
+    assert!(!lock.is_poisoned());
+    assert_eq!(*lock.lock().unwrap(), 2);
+  }
+
+  #[test]
+  fn condvar_wait_poisoned() {
+    let mut q = Queue::new();
+    let pair = Arc::new((Mutex::new(()), Condvar::new()));
+    let (p1, p2) = (pair.clone(), pair.clone());
+
+    q.push_back(thread(move || {
+      let &(ref lock, ref cvar) = &*p1;
+      let g = lock.lock().unwrap();
+      assert!(cvar.wait(g).is_err());
+    }));
+    q.push_back(thread(move || {
+      let &(ref lock, ref cvar) = &*p2;
+      let _g = lock.lock().unwrap();
+      cvar.notify_one();
+      panic!("poison while the waiter sleeps");
+    }));
+
+    Scheduler::new(q).run();
+  }
+
+  #[test]
+  fn unwinding_thread_doesnt_poison_others() {
+    // Switches away halfway through unwinding.
+    struct YieldOnDrop;
+    impl Drop for YieldOnDrop {
+      fn drop(&mut self) {
+        Thread::suspend(Request::Yield);
+      }
+    }
+
+    let mut q = Queue::new();
+    let lock = Arc::new(Mutex::new(0));
+    let l1 = lock.clone();
+
+    q.push_back(thread(move || {
+      let mut g = l1.lock().unwrap();
+      Thread::suspend(Request::Yield);
+      *g += 1;
+    }));
+    q.push_back(thread(move || {
+      let _y = YieldOnDrop;
+      panic!("unwind through a switch");
+    }));
+
+    Scheduler::new(q).run();
+    assert!(!lock.is_poisoned());
+    assert_eq!(*lock.lock().unwrap(), 1);
+  }
+
+  #[test]
+  fn caught_panic_doesnt_stop_poisoning() {
+    let mut q = Queue::new();
+    let lock = Arc::new(Mutex::new(0));
+    let l1 = lock.clone();
+
+    q.push_back(thread(move || {
+      assert!(::std::panic::catch_unwind(|| panic!("caught")).is_err());
+      Thread::suspend(Request::Yield);
+      let _g = l1.lock().unwrap();
+      panic!("poison the lock");
+    }));
+    q.push_back(thread(|| {}));
+
+    Scheduler::new(q).run();
+    assert!(lock.is_poisoned());
+  }
+
+  #[test]
+  #[should_panic(expected = "joined thread panicked")]
+  fn join_panicked() {
+    let mut q = Queue::new();
+    let (child, handle) = ::thread::new::<Unit, _, _>(OwnedStack::new(1024 * 1024), || {
+      panic!("child panics");
+    });
+    q.push_back(child);
+    Scheduler::new(q).run();
+    let () = handle.join();
+  }
+
+  #[test]
+  fn preempt_busy_thread() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+    use preempt;
+
+    let mut q = Queue::new();
+    let flag = Arc::new(AtomicBool::new(false));
+    let (f1, f2) = (flag.clone(), flag.clone());
+
+    // Never yields, so only preemption lets the second thread run.
+    q.push_back(thread(move || {
+      while !f1.load(Ordering::SeqCst) {}
+    }));
+    q.push_back(thread(move || {
+      f2.store(true, Ordering::SeqCst);
+    }));
+
+    let _quantum = Quantum::set(2);
+    let _timer = preempt::Timer::start::<Unit>(Duration::from_millis(1));
+    Scheduler::new(q).run();
+    assert!(flag.load(Ordering::SeqCst));
+  }
+
+  // Sets the preemption quantum until dropped, one test at a time.
+  struct Quantum {
+    old: usize,
+    _serial: ::spin::MutexGuard<'static, ()>,
+  }
+
+  static QUANTUM_TESTS: ::spin::Mutex<()> = ::spin::Mutex::new(());
+
+  impl Quantum {
+
+    fn set(ticks: usize) -> Quantum {
+      let serial = QUANTUM_TESTS.lock();
+      Quantum { old: ::preempt::set_quantum(ticks), _serial: serial }
+    }
+
+  }
+
+  impl Drop for Quantum {
+
+    fn drop(&mut self) {
+      ::preempt::set_quantum(self.old);
+    }
+
+  }
+
+  #[test]
+  fn preempt_waits_for_spin_locks() {
+    use preempt::preempt_tick;
+    use spin_lock::SpinLock;
+
+    let _quantum = Quantum::set(2);
+    let mut q = Queue::new();
+    let order = Arc::new(::std::sync::Mutex::new(vec!()));
+    let (o1, o2) = (order.clone(), order.clone());
+    q.push_back(thread(move || {
+      let lock = SpinLock::new(());
+      let g = lock.lock();
+      // As if the timer went off while we held the lock.
+      for _ in 0..10 {
+        preempt_tick::<Unit>();
+      }
+      o1.lock().unwrap().push("held");
+      drop(g);
+      preempt_tick::<Unit>();
+      o1.lock().unwrap().push("after");
+    }));
+    q.push_back(thread(move || o2.lock().unwrap().push("other")));
+
+    Scheduler::new(q).run();
+    assert_eq!(*order.lock().unwrap(), vec!("held", "other", "after"));
+  }
+
+  #[test]
+  fn preempt_waits_for_no_preempt() {
+    use preempt::{no_preempt, preempt_tick};
+
+    let _quantum = Quantum::set(2);
+    let mut q = Queue::new();
+    let order = Arc::new(::std::sync::Mutex::new(vec!()));
+    let (o1, o2) = (order.clone(), order.clone());
+    q.push_back(thread(move || {
+      no_preempt(|| {
+        for _ in 0..10 {
+          preempt_tick::<Unit>();
+        }
+        o1.lock().unwrap().push("inside");
+      });
+      preempt_tick::<Unit>();
+      o1.lock().unwrap().push("after");
+    }));
+    q.push_back(thread(move || o2.lock().unwrap().push("other")));
+
+    Scheduler::new(q).run();
+    assert_eq!(*order.lock().unwrap(), vec!("inside", "other", "after"));
+  }
+
+  #[test]
+  fn preempted_threads_share_a_mutex() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+    use preempt;
+
+    let mut q = Queue::new();
+    let count = Arc::new(Mutex::new(0));
+    let total = Arc::new(AtomicUsize::new(0));
+    for _ in 0..3 {
+      let (count, total) = (count.clone(), total.clone());
+      q.push_back(thread(move || {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(50) {
+          *count.lock().unwrap() += 1;
+          total.fetch_add(1, Ordering::SeqCst);
+        }
+      }));
+    }
+
+    let _quantum = Quantum::set(1);
+    let _timer = preempt::Timer::start::<Unit>(Duration::from_millis(1));
+    Scheduler::new(q).run();
+    assert_eq!(*count.lock().unwrap(), total.load(Ordering::SeqCst));
+  }
+
+  #[test]
+  fn semaphore_limits_concurrency() {
+    let mut q = Queue::new();
+    let sem = Arc::new(Semaphore::new(2));
+    let inside = Arc::new(::std::sync::Mutex::new((0, 0)));
+
+    for _ in 0..5 {
+      let (sem, inside) = (sem.clone(), inside.clone());
+      q.push_back(thread(move || {
+        let _permit = sem.acquire();
+        {
+          let mut i = inside.lock().unwrap();
+          i.0 += 1;
+          if i.0 > i.1 { i.1 = i.0; }
+        }
+        Thread::suspend(Request::Yield);
+        inside.lock().unwrap().0 -= 1;
+      }));
+    }
+
+    Scheduler::new(q).run();
+    assert_eq!(inside.lock().unwrap().1, 2);
+    assert_eq!(sem.available_permits(), 2);
+  }
+
+  #[test]
+  fn semaphore_acquire_many() {
+    let mut q = Queue::new();
+    let sem = Arc::new(Semaphore::new(1));
+    let order = Arc::new(::std::sync::Mutex::new(vec!()));
+    let (s1, s2, o1, o2) = (sem.clone(), sem.clone(), order.clone(), order.clone());
+
+    q.push_back(thread(move || {
+      let _permits = s1.acquire_many(3);
+      o1.lock().unwrap().push("many");
+    }));
+    q.push_back(thread(move || {
+      assert!(s2.try_acquire_many(2).is_none());
+      s2.release(2);
+      o2.lock().unwrap().push("released");
+    }));
+
+    Scheduler::new(q).run();
+    assert_eq!(*order.lock().unwrap(), vec!("released", "many"));
+    assert_eq!(sem.available_permits(), 3);
+  }
+
+  #[test]
+  fn channel_smoke() {
+    let mut q = Queue::new();
+    let (tx, rx) = channel();
+    let received = Arc::new(::std::sync::Mutex::new(vec!()));
+    let saved = received.clone();
+
+    q.push_back(thread(move || {
+      while let Ok(v) = rx.recv() {
+        received.lock().unwrap().push(v);
+      }
+    }));
+    for i in 0..3 {
+      let tx = tx.clone();
+      q.push_back(thread(move || {
+        tx.send(i).unwrap();
+        Thread::suspend(Request::Yield);
+        tx.send(i + 10).unwrap();
+      }));
+    }
+    drop(tx);
+
+    Scheduler::new(q).run();
+    let mut received = saved.lock().unwrap().clone();
+    received.sort();
+    assert_eq!(received, vec!(0, 1, 2, 10, 11, 12));
+  }
+
+  #[test]
+  fn sync_channel_backpressure() {
+    let mut q = Queue::new();
+    let (tx, rx) = sync_channel(1);
+    let log = Arc::new(::std::sync::Mutex::new(vec!()));
+    let (l1, l2) = (log.clone(), log.clone());
+
+    q.push_back(thread(move || {
+      for i in 0..3 {
+        tx.send(i).unwrap();
+        l1.lock().unwrap().push("sent");
+      }
+    }));
+    q.push_back(thread(move || {
+      for i in 0..3 {
+        assert_eq!(rx.recv(), Ok(i));
+        l2.lock().unwrap().push("received");
+      }
+      assert_eq!(rx.recv(), Err(::channel::RecvError));
+    }));
+
+    Scheduler::new(q).run();
+    // The sender never gets more than one value ahead.
+    assert_eq!(*log.lock().unwrap(),
+               vec!("sent", "received", "sent", "received", "sent", "received"));
+  }
+
+  #[test]
+  fn channel_receiver_dropped() {
+    let (tx, rx) = sync_channel(1);
+    assert_eq!(tx.try_send(1), Ok(()));
+    assert_eq!(tx.try_send(2), Err(::channel::TrySendError::Full(2)));
+    drop(rx);
+    assert_eq!(tx.send(3), Err(::channel::SendError(3)));
+  }
+
+  #[test]
+  fn oneshot_smoke() {
+    let mut q = Queue::new();
+    let (tx, rx) = oneshot();
+    let (dropped_tx, dropped_rx) = oneshot::<u32>();
+    let result = Arc::new(::std::sync::Mutex::new(None));
+    let saved = result.clone();
+
+    q.push_back(thread(move || {
+      *result.lock().unwrap() = Some((rx.recv(), dropped_rx.recv()));
+    }));
+    q.push_back(thread(move || {
+      tx.send(7).unwrap();
+      drop(dropped_tx);
+    }));
+
+    Scheduler::new(q).run();
+    assert_eq!(*saved.lock().unwrap(), Some((Ok(7), Err(::channel::RecvError))));
+  }
+
+  #[test]
+  fn select_smoke() {
+    let mut q = Queue::new();
+    let (tx1, rx1) = channel::<u32>();
+    let (tx2, rx2) = oneshot();
+    let picked = Arc::new(::std::sync::Mutex::new(vec!()));
+    let saved = picked.clone();
+
+    q.push_back(thread(move || {
+      let mut picked = picked.lock().unwrap();
+      let i = ::select::select::<Unit>(&[&rx1, &rx2]);
+      picked.push(i);
+      assert_eq!(rx2.recv(), Ok("done"));
+      // The waker fired by the oneshot must be gone from the channel.
+      picked.push(::select::select::<Unit>(&[&rx1]));
+      assert_eq!(rx1.try_recv(), Err(::channel::TryRecvError::Disconnected));
+    }));
+    q.push_back(thread(move || {
+      tx2.send("done").unwrap();
+      Thread::suspend(Request::Yield);
+      drop(tx1);
+    }));
+
+    Scheduler::new(q).run();
+    assert_eq!(*saved.lock().unwrap(), vec!(1, 0));
+  }
+
+  #[test]
+  fn select_on_locks() {
+    let mut q = Queue::new();
+    let lock = Arc::new(Mutex::new(0));
+    let sem = Arc::new(Semaphore::new(0));
+    let cvar = Arc::new(Condvar::new());
+    let (l1, s1, c1) = (lock.clone(), sem.clone(), cvar.clone());
+    let (l2, s2) = (lock.clone(), sem.clone());
+    let picked = Arc::new(::std::sync::Mutex::new(vec!()));
+    let saved = picked.clone();
+
+    q.push_back(thread(move || {
+      let g = l1.lock().unwrap();
+      Thread::suspend(Request::Yield);
+      drop(g);
+      Thread::suspend(Request::Yield);
+      s1.release(1);
+      Thread::suspend(Request::Yield);
+      // Nobody waits on the condvar, so the notification goes to select.
+      c1.notify_one();
+    }));
+    q.push_back(thread(move || {
+      let (lock, sem) = (&*l2, &*s2);
+      let mut picked = picked.lock().unwrap();
+      picked.push(select! {
+        guard = lock.lock() => { *guard.unwrap() += 1; 0 },
+        permit = sem.acquire() => { permit.forget(); 1 },
+      });
+      picked.push(select! {
+        permit = sem.acquire() => { permit.forget(); 1 }
+      });
+      picked.push(::select::select::<Unit>(&[&*cvar, sem]));
+    }));
+
+    Scheduler::new(q).run();
+    assert_eq!(*saved.lock().unwrap(), vec!(0, 1, 0));
+    assert_eq!(*lock.lock().unwrap(), 1);
+  }
+
+  #[test]
+  fn smp_shared_mutex() {
+    let counter = Arc::new(Mutex::new(0));
+    let queues = (0..2).map(|_| {
+      let mut q = Queue::new();
+      for _ in 0..4 {
+        let counter = counter.clone();
+        q.push_back(thread(move || {
+          for _ in 0..100 {
+            let mut count = counter.lock().unwrap();
+            *count += 1;
+            // Hold the lock across a switch so that threads on both CPUs park.
+            Thread::suspend(Request::Yield);
+          }
+        }));
+      }
+      q
+    }).collect();
+
+    ::scheduler::run_smp::<Unit>(queues);
+    assert_eq!(*counter.lock().unwrap(), 800);
+  }
+
+  #[test]
+  fn smp_channel() {
+    let (tx, rx) = sync_channel(2);
+    let sum = Arc::new(::std::sync::Mutex::new(0));
+    let saved = sum.clone();
+    let mut q1 = Queue::new();
+    let mut q2 = Queue::new();
+    q1.push_back(thread(move || {
+      while let Ok(v) = rx.recv() {
+        *sum.lock().unwrap() += v;
+      }
+    }));
+    q2.push_back(thread(move || {
+      for i in 0..100 {
+        tx.send(i).unwrap();
+      }
+    }));
+
+    ::scheduler::run_smp::<Unit>(vec!(q1, q2));
+    assert_eq!(*saved.lock().unwrap(), 4950);
+  }
+
+  #[test]
+  fn smp_steals_from_busy_cpu() {
+    use std::collections::HashSet;
+
+    thread_local!(static CPU: u8 = 0);
+    // Tells OS threads, and so CPUs, apart.
+    fn cpu() -> usize {
+      CPU.with(|c| c as *const u8 as usize)
+    }
+
+    let ran_on = Arc::new(::std::sync::Mutex::new(HashSet::new()));
+    let pinned_ran_on = Arc::new(::std::sync::Mutex::new(HashSet::new()));
+    let mut busy = Queue::new();
+    for i in 0..9 {
+      let ran_on = if i == 0 { pinned_ran_on.clone() } else { ran_on.clone() };
+      let mut t = thread(move || {
+        for _ in 0..50 {
+          ran_on.lock().unwrap().insert(cpu());
+          Thread::suspend(Request::Yield);
+        }
+      });
+      if i == 0 {
+        t.local_mut().set_affinity(1);
+      }
+      busy.push_back(t);
+    }
+
+    ::scheduler::run_smp_balanced::<Unit>(vec!(busy, Queue::new()));
+    assert_eq!(ran_on.lock().unwrap().len(), 2);
+    assert_eq!(pinned_ran_on.lock().unwrap().len(), 1);
+  }
+
+  #[test]
+  fn forever_idles_until_shutdown() {
+    use std::boxed::Box;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+    use scheduler::{Cpu, CondvarIdle};
+
+    // Both only have to outlive the scheduler, and are freed at the end.
+    let cpus = Box::into_raw(vec!(Cpu::new()).into_boxed_slice());
+    let idle = Box::into_raw(Box::new(CondvarIdle::new(Duration::from_millis(10))));
+    let (cpus, idle): (&'static [Cpu<Unit>], &'static CondvarIdle) = unsafe { (&*cpus, &*idle) };
+    let ran = Arc::new(AtomicBool::new(false));
+    let saved_ran = ran.clone();
+
+    let cpu = ::std::thread::spawn(move || {
+      Scheduler::new_smp(Queue::new(), cpus, 0).forever(idle).run();
+    });
+    // With nothing to run, the scheduler must wait rather than return.
+    ::std::thread::sleep(Duration::from_millis(30));
+    cpus[0].schedule(<Node as ::scheduler::Node<Unit>>::new(thread(move || {
+      ran.store(true, Ordering::SeqCst);
+      Thread::suspend(Request::Shutdown);
+    })));
+    cpu.join().unwrap();
+    assert!(saved_ran.load(Ordering::SeqCst));
+    unsafe {
+      drop(Box::from_raw(cpus as *const [Cpu<Unit>] as *mut [Cpu<Unit>]));
+      drop(Box::from_raw(idle as *const CondvarIdle as *mut CondvarIdle));
+    }
+  }
+
+  #[test]
+  fn irq_waker_from_signal_handler() {
+    use std::boxed::Box;
+    use std::mem;
+    use std::ptr;
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+    use std::time::Duration;
+    use libc;
+    use scheduler::CondvarIdle;
+
+    static WAKER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    extern "C" fn on_usr1(_: libc::c_int) {
+      let waker = WAKER.load(Ordering::SeqCst) as *const IrqWaker;
+      if !waker.is_null() {
+        unsafe { (*waker).wake() };
+      }
+    }
+
+    // Both only have to outlive the scheduler, and are freed at the end.
+    let waker = Box::into_raw(Box::new(IrqWaker::new()));
+    let idle = Box::into_raw(Box::new(CondvarIdle::new(Duration::from_millis(1))));
+    WAKER.store(waker as usize, Ordering::SeqCst);
+    let (waker, idle): (&'static IrqWaker, &'static CondvarIdle) = unsafe { (&*waker, &*idle) };
+    unsafe {
+      let mut action: libc::sigaction = mem::zeroed();
+      action.sa_sigaction = on_usr1 as libc::sighandler_t;
+      action.sa_flags = libc::SA_RESTART;
+      libc::sigemptyset(&mut action.sa_mask);
+      libc::sigaction(libc::SIGUSR1, &action, ptr::null_mut());
+    }
+
+    let target = unsafe { libc::pthread_self() };
+    let interrupts = ::std::thread::spawn(move || {
+      for _ in 0..3 {
+        ::std::thread::sleep(Duration::from_millis(10));
+        unsafe { libc::pthread_kill(target, libc::SIGUSR1) };
+      }
+    });
+
+    let woken = Arc::new(::std::sync::Mutex::new(0));
+    let saved = woken.clone();
+    let mut q = Queue::new();
+    q.push_back(thread(move || {
+      for _ in 0..3 {
+        waker.wait();
+        *woken.lock().unwrap() += 1;
+      }
+      Thread::suspend(Request::Shutdown);
+    }));
+    Scheduler::new(q).forever(idle).run();
+    interrupts.join().unwrap();
+    assert_eq!(*saved.lock().unwrap(), 3);
+    WAKER.store(0, Ordering::SeqCst);
+    unsafe {
+      drop(Box::from_raw(waker as *const IrqWaker as *mut IrqWaker));
+      drop(Box::from_raw(idle as *const CondvarIdle as *mut CondvarIdle));
+    }
+  }
+
+  #[test]
+  fn thread_ids_and_inspect() {
+    use scheduler::{State, ThreadInfo};
+
+    let mut q = Queue::new();
+    let lock = Arc::new(Mutex::new(()));
+    let infos = Arc::new(::std::sync::Mutex::new(vec!()));
+    let (l1, l2, saved) = (lock.clone(), lock.clone(), infos.clone());
+
+    let holder = thread(move || {
+      let _guard = l1.lock().unwrap();
+      Thread::suspend(Request::Yield);
+      Thread::suspend(Request::Yield);
+    }).with_name("holder");
+    let holder_id = holder.id();
+    let waiter = thread(move || {
+      let _guard = l2.lock().unwrap();
+    }).with_name("waiter");
+    let waiter_id = waiter.id();
+    assert!(holder_id != waiter_id);
+    q.push_back(holder);
+    q.push_back(waiter);
+    q.push_back(thread(move || {
+      assert_eq!(Thread::current().name(), Some("inspector"));
+      let mut found = vec!();
+      Thread::inspect(&mut |info: ThreadInfo| found.push(info));
+      *infos.lock().unwrap() = found;
+    }).with_name("inspector"));
+
+    Scheduler::new(q).run();
+    let infos = saved.lock().unwrap();
+    assert_eq!(infos.len(), 3);
+    assert_eq!(infos[0].name, Some("inspector"));
+    assert_eq!(infos[0].state, State::Running);
+    assert_eq!((infos[1].id, infos[1].state), (holder_id, State::Ready));
+    let mutex = ::scheduler::BlockedOn::new("mutex", &*lock);
+    assert_eq!((infos[2].id, infos[2].state), (waiter_id, State::Blocked(Some(mutex))));
+  }
+
+  #[test]
+  fn builder_recycles_pooled_stacks() {
+    let mut q = Queue::new();
+    let pool = Arc::new(StackPool::new(64 * 1024, 4));
+    let saved_pool = pool.clone();
+
+    q.push_back(thread(move || {
+      for i in 0..100 {
+        let handle = Builder::new().name("short").stack_pool(pool.clone()).spawn(move || {
+          assert_eq!(Thread::current().name(), Some("short"));
+          i * 2
+        });
+        assert_eq!(handle.join(), i * 2);
+      }
+    }));
+
+    Scheduler::new(q).run();
+    // Each thread finished before the next was spawned, so one stack did.
+    assert_eq!(saved_pool.allocated(), 1);
+    assert_eq!(saved_pool.len(), 1);
+  }
+
+  #[test]
+  fn stack_overflow_kills_thread() {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+    use stack::{set_overflow_policy, OverflowPolicy};
+    use scheduler::ThreadId;
+
+    static OVERFLOWED: AtomicUsize = ATOMIC_USIZE_INIT;
+    fn on_overflow(id: ThreadId, name: Option<&'static str>) {
+      assert_eq!(name, Some("deep"));
+      OVERFLOWED.store(id.as_usize(), Ordering::SeqCst);
+    }
+    set_overflow_policy(OverflowPolicy::Callback(on_overflow));
+
+    let mut q = Queue::new();
+    let resumed = Arc::new(AtomicBool::new(false));
+    let their_resumed = resumed.clone();
+    let t = thread(move || {
+      // Pretend to have run off the end of the stack.
+      let limit = Thread::current().stack_limit();
+      unsafe { ::core::ptr::write_bytes(limit as *mut u8, 0, 64) };
+      Thread::suspend(Request::Yield);
+      their_resumed.store(true, Ordering::SeqCst);
+    }).with_name("deep");
+    let id = t.id();
+    q.push_back(t);
+    q.push_back(thread(|| Thread::suspend(Request::Yield)));
+
+    Scheduler::new(q).run();
+    assert_eq!(OVERFLOWED.load(Ordering::SeqCst), id.as_usize());
+    assert!(!resumed.load(Ordering::SeqCst));
+
+    // The request the thread made as it overflowed still goes through: a
+    // thread it schedules runs, and a lock it parks on is let go.
+    let mut q = Queue::new();
+    let lock = Arc::new(Mutex::new(()));
+    let ran = Arc::new(AtomicBool::new(false));
+    let (l1, l2, l3, their_ran) = (lock.clone(), lock.clone(), lock.clone(), ran.clone());
+    q.push_back(thread(move || {
+      let g = l1.lock().unwrap();
+      Thread::suspend(Request::Yield);
+      drop(g);
+    }));
+    q.push_back(thread(move || {
+      let t = thread(move || their_ran.store(true, Ordering::SeqCst));
+      let limit = Thread::current().stack_limit();
+      unsafe { ::core::ptr::write_bytes(limit as *mut u8, 0, 64) };
+      Thread::suspend(Request::Schedule(Node::new(t)));
+    }).with_name("deep"));
+    q.push_back(thread(move || {
+      let limit = Thread::current().stack_limit();
+      unsafe { ::core::ptr::write_bytes(limit as *mut u8, 0, 64) };
+      let _g = l2.lock().unwrap();
+      unreachable!();
+    }).with_name("deep"));
+    q.push_back(thread(move || {
+      Thread::suspend(Request::Yield);
+      let _g = l3.lock().unwrap();
+    }));
+
+    Scheduler::new(q).run();
+    set_overflow_policy(OverflowPolicy::Panic);
+    assert!(ran.load(Ordering::SeqCst));
+    assert!(lock.try_lock().is_ok());
+  }
+
+  #[test]
+  fn stack_high_water_mark() {
+    use scheduler::ThreadInfo;
+
+    fn deep(depth: usize) -> u8 {
+      let frame = [depth as u8; 4096];
+      if depth == 0 { frame[0] } else { deep(depth - 1).wrapping_add(frame[4095]) }
+    }
+
+    let mut q = Queue::new();
+    let infos = Arc::new(::std::sync::Mutex::new(vec!()));
+    let saved = infos.clone();
+    let painted = |f: fn()| Thread::new_painted(OwnedStack::new(1024 * 1024), f);
+    let fresh = painted(|| {});
+    let fresh_usage = fresh.stack_usage();
+    assert_eq!(fresh_usage.size, 1024 * 1024);
+    q.push_back(fresh);
+    q.push_back(painted(|| {
+      deep(16);
+      Thread::suspend(Request::Yield);
+    }).with_name("deep"));
+    q.push_back(thread(|| {}).with_name("unpainted"));
+    q.push_back(thread(move || {
+      let mut found = vec!();
+      Thread::inspect(&mut |info: ThreadInfo| found.push(info));
+      *infos.lock().unwrap() = found;
+    }));
+
+    Scheduler::new(q).run();
+    assert!(fresh_usage.used.unwrap() < 4096);
+    let infos = saved.lock().unwrap();
+    let deep = infos.iter().find(|info| info.name == Some("deep")).unwrap();
+    assert!(deep.stack.used.unwrap() >= 16 * 4096);
+    assert!(deep.stack.used.unwrap() < deep.stack.size);
+    let unpainted = infos.iter().find(|info| info.name == Some("unpainted")).unwrap();
+    assert_eq!(unpainted.stack.used, None);
+  }
+
+  #[test]
+  fn queue_removes_from_the_middle() {
+    let (a, b, c) = (thread(|| {}), thread(|| {}), thread(|| {}));
+    let (a_id, b_id, c_id) = (a.id(), b.id(), c.id());
+    let mut q = Queue::new();
+    let mut other = Queue::new();
+    q.push_back(a);
+    q.push_back(b);
+    q.push_back(c);
+
+    let b: *const Thread = {
+      let mut found = None;
+      q.for_each(&mut |t| if t.id() == b_id { found = Some(t as *const Thread) });
+      found.unwrap()
+    };
+    assert!(other.remove(unsafe { &*b }).is_none());
+    let b = q.remove(unsafe { &*b }).unwrap();
+    assert!(!q.contains(&b));
+    assert_eq!(q.len(), 2);
+    other.push_back(b);
+
+    let mut ids = vec!();
+    q.for_each(&mut |t| ids.push(t.id()));
+    assert_eq!(ids, vec!(a_id, c_id));
+    let mut s = Scheduler::new(q);
+    s.run();
+    Scheduler::new(other).run();
+  }
+
 }
@@ -0,0 +1,466 @@
+// Message passing between threads: unbounded and bounded multi-producer,
+// single-consumer channels, and oneshot channels.
+//
+// Blocked receivers and senders park on `U::Q` wait queues. As with the locks,
+// the spin lock protecting a channel is dropped before anyone is woken.
+
+extern crate alloc;
+
+use self::alloc::arc::Arc;
+use core::mem::swap;
+
+use linked_list::LinkedList;
+use preempt::no_preempt;
+use scheduler::{Thread, Request, SchedulerUnit, Queue, BlockedOn};
+use select::{Selectable, Waker, Watchers};
+
+/// Returned by `send` when the receiver is gone; holds the unsent value.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+/// Returned by `try_send` when the value can't be sent right away.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+  Full(T),
+  Disconnected(T),
+}
+
+/// Returned by `recv` when the channel is empty and every sender is gone.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RecvError;
+
+/// Returned by `try_recv` when there is no value to take.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TryRecvError {
+  Empty,
+  Disconnected,
+}
+
+struct Shared<T, U: SchedulerUnit> {
+  state: ::spin_lock::SpinLock<State<T, U>>,
+}
+
+struct State<T, U: SchedulerUnit> {
+  buf: LinkedList<T>,
+  // `None` for unbounded channels.
+  bound: Option<usize>,
+  senders: usize,
+  receiver: bool,
+  recv_queue: U::Q,
+  // Threads selecting on the receiver.
+  watchers: Watchers<U>,
+  // Senders waiting for room in a full bounded channel.
+  send_queue: U::Q,
+}
+
+unsafe impl<T: Send, U: SchedulerUnit> Send for Shared<T, U> {}
+unsafe impl<T: Send, U: SchedulerUnit> Sync for Shared<T, U> {}
+
+/// The sending half of an unbounded channel. Sending never blocks.
+pub struct Sender<T, U: SchedulerUnit> {
+  inner: Arc<Shared<T, U>>,
+}
+
+/// The sending half of a bounded channel. Sending blocks while it is full.
+pub struct SyncSender<T, U: SchedulerUnit> {
+  inner: Arc<Shared<T, U>>,
+}
+
+/// The receiving half of a channel.
+pub struct Receiver<T, U: SchedulerUnit> {
+  inner: Arc<Shared<T, U>>,
+}
+
+fn shared<T, U: SchedulerUnit>(bound: Option<usize>) -> Arc<Shared<T, U>> {
+  no_preempt(|| Arc::new(Shared { state: ::spin_lock::SpinLock::new(State {
+                      buf: LinkedList::new(),
+                      bound: bound,
+                      senders: 1,
+                      receiver: true,
+                      recv_queue: U::Q::new(),
+                      watchers: Watchers::new(),
+                      send_queue: U::Q::new(),
+                    }) }))
+}
+
+/// Creates an unbounded channel.
+pub fn channel<T, U: SchedulerUnit>() -> (Sender<T, U>, Receiver<T, U>) {
+  let inner = shared(None);
+  (Sender { inner: inner.clone() }, Receiver { inner: inner })
+}
+
+/// Creates a channel holding at most `bound` values; `bound` must not be 0.
+pub fn sync_channel<T, U: SchedulerUnit>(bound: usize) -> (SyncSender<T, U>, Receiver<T, U>) {
+  assert!(bound > 0, "sync_channel bound must not be 0");
+  let inner = shared(Some(bound));
+  (SyncSender { inner: inner.clone() }, Receiver { inner: inner })
+}
+
+fn wake<U: SchedulerUnit>(node: Option<U::N>) {
+  if let Some(node) = node {
+    Thread::<U>::suspend(Request::Schedule(node));
+  }
+}
+
+fn wake_all<U: SchedulerUnit>(mut queue: U::Q) {
+  while let Some(node) = queue.pop() {
+    Thread::<U>::suspend(Request::Schedule(node));
+  }
+}
+
+impl<T, U: SchedulerUnit> State<T, U> {
+
+  // Takes the receiver and any selecting threads off their queues after a
+  // value has been sent.
+  fn receiver_woken(&mut self) -> U::Q {
+    let mut woken = U::Q::new();
+    if let Some(node) = self.recv_queue.pop() {
+      woken.push(node);
+    }
+    self.watchers.fire_all(&mut woken);
+    woken
+  }
+
+}
+
+impl<T, U: SchedulerUnit> Shared<T, U> {
+
+  fn send(&self, t: T) -> Result<(), SendError<T>> {
+    loop {
+      let mut state = self.state.lock();
+      if !state.receiver {
+        return Err(SendError(t));
+      }
+      let full = match state.bound {
+        Some(bound) => state.buf.len() >= bound,
+        None => false,
+      };
+      if !full {
+        state.buf.push_back(t);
+        let woken = state.receiver_woken();
+        drop(state);
+        wake_all::<U>(woken);
+        return Ok(());
+      }
+      debug!("channel full, sleeping");
+      let take = move |me| {
+        state.send_queue.push(me);
+        drop(state);
+      };
+      Thread::<U>::park(BlockedOn::new("channel", self), &take);
+    }
+  }
+
+  fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+    let mut state = self.state.lock();
+    if !state.receiver {
+      return Err(TrySendError::Disconnected(t));
+    }
+    if let Some(bound) = state.bound {
+      if state.buf.len() >= bound {
+        return Err(TrySendError::Full(t));
+      }
+    }
+    state.buf.push_back(t);
+    let woken = state.receiver_woken();
+    drop(state);
+    wake_all::<U>(woken);
+    Ok(())
+  }
+
+  fn add_sender(&self) {
+    self.state.lock().senders += 1;
+  }
+
+  fn drop_sender(&self) {
+    let mut receivers = U::Q::new();
+    let mut state = self.state.lock();
+    state.senders -= 1;
+    if state.senders == 0 {
+      swap(&mut state.recv_queue, &mut receivers);
+      state.watchers.fire_all(&mut receivers);
+    }
+    drop(state);
+    wake_all::<U>(receivers);
+  }
+
+}
+
+impl<T, U: SchedulerUnit> Sender<T, U> {
+
+  /// Sends `t`, failing only if the receiver has been dropped.
+  pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+    self.inner.send(t)
+  }
+
+}
+
+impl<T, U: SchedulerUnit> Clone for Sender<T, U> {
+
+  fn clone(&self) -> Sender<T, U> {
+    self.inner.add_sender();
+    Sender { inner: self.inner.clone() }
+  }
+
+}
+
+impl<T, U: SchedulerUnit> Drop for Sender<T, U> {
+
+  fn drop(&mut self) {
+    self.inner.drop_sender();
+  }
+
+}
+
+impl<T, U: SchedulerUnit> SyncSender<T, U> {
+
+  /// Sends `t`, waiting for room if the channel is full.
+  pub fn send(&self, t: T) -> Result<(), SendError<T>> {
+    self.inner.send(t)
+  }
+
+  pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+    self.inner.try_send(t)
+  }
+
+}
+
+impl<T, U: SchedulerUnit> Clone for SyncSender<T, U> {
+
+  fn clone(&self) -> SyncSender<T, U> {
+    self.inner.add_sender();
+    SyncSender { inner: self.inner.clone() }
+  }
+
+}
+
+impl<T, U: SchedulerUnit> Drop for SyncSender<T, U> {
+
+  fn drop(&mut self) {
+    self.inner.drop_sender();
+  }
+
+}
+
+impl<T, U: SchedulerUnit> Receiver<T, U> {
+
+  pub fn try_recv(&self) -> Result<T, TryRecvError> {
+    let mut state = self.inner.state.lock();
+    let value = state.buf.pop_front();
+    match value {
+      Some(t) => {
+        let sender = state.send_queue.pop();
+        drop(state);
+        wake::<U>(sender);
+        Ok(t)
+      }
+      None if state.senders == 0 => Err(TryRecvError::Disconnected),
+      None => Err(TryRecvError::Empty),
+    }
+  }
+
+  /// Waits for a value. Values sent before the last sender was dropped are
+  /// still received before this starts failing.
+  pub fn recv(&self) -> Result<T, RecvError> {
+    loop {
+      let mut state = self.inner.state.lock();
+      let value = state.buf.pop_front();
+      if let Some(t) = value {
+        let sender = state.send_queue.pop();
+        drop(state);
+        wake::<U>(sender);
+        return Ok(t);
+      }
+      if state.senders == 0 {
+        return Err(RecvError);
+      }
+      debug!("channel empty, sleeping");
+      let take = move |me| {
+        state.recv_queue.push(me);
+        drop(state);
+      };
+      Thread::<U>::park(BlockedOn::new("channel", &*self.inner), &take);
+    }
+  }
+
+}
+
+impl<T, U: SchedulerUnit> Selectable<U> for Receiver<T, U> {
+
+  fn register(&self, waker: &Arc<Waker<U>>, index: usize) -> bool {
+    let mut state = self.inner.state.lock();
+    if !state.buf.is_empty() || state.senders == 0 {
+      return true;
+    }
+    state.watchers.add(waker, index);
+    false
+  }
+
+  fn unregister(&self, waker: &Arc<Waker<U>>) {
+    self.inner.state.lock().watchers.remove(waker);
+  }
+
+}
+
+impl<T, U: SchedulerUnit> Drop for Receiver<T, U> {
+
+  fn drop(&mut self) {
+    let mut senders = U::Q::new();
+    let mut buf = LinkedList::new();
+    let mut state = self.inner.state.lock();
+    state.receiver = false;
+    swap(&mut state.send_queue, &mut senders);
+    // Unreceived values are dropped once the lock is released.
+    swap(&mut state.buf, &mut buf);
+    drop(state);
+    no_preempt(move || drop(buf));
+    wake_all::<U>(senders);
+  }
+
+}
+
+unsafe impl<T: Send, U: SchedulerUnit> Send for Sender<T, U> {}
+unsafe impl<T: Send, U: SchedulerUnit> Send for SyncSender<T, U> {}
+unsafe impl<T: Send, U: SchedulerUnit> Sync for SyncSender<T, U> {}
+unsafe impl<T: Send, U: SchedulerUnit> Send for Receiver<T, U> {}
+
+struct Oneshot<T, U: SchedulerUnit> {
+  state: ::spin_lock::SpinLock<OneshotState<T, U>>,
+}
+
+struct OneshotState<T, U: SchedulerUnit> {
+  value: Option<T>,
+  sender: bool,
+  receiver: bool,
+  waiter: Option<U::N>,
+  watchers: Watchers<U>,
+}
+
+unsafe impl<T: Send, U: SchedulerUnit> Send for Oneshot<T, U> {}
+unsafe impl<T: Send, U: SchedulerUnit> Sync for Oneshot<T, U> {}
+
+impl<T, U: SchedulerUnit> OneshotState<T, U> {
+
+  fn woken(&mut self) -> U::Q {
+    let mut woken = U::Q::new();
+    if let Some(node) = self.waiter.take() {
+      woken.push(node);
+    }
+    self.watchers.fire_all(&mut woken);
+    woken
+  }
+
+}
+
+/// The sending half of a oneshot channel.
+pub struct OneshotSender<T, U: SchedulerUnit> {
+  inner: Arc<Oneshot<T, U>>,
+}
+
+/// The receiving half of a oneshot channel.
+pub struct OneshotReceiver<T, U: SchedulerUnit> {
+  inner: Arc<Oneshot<T, U>>,
+}
+
+/// Creates a channel that carries a single value.
+pub fn oneshot<T, U: SchedulerUnit>() -> (OneshotSender<T, U>, OneshotReceiver<T, U>) {
+  let inner = no_preempt(|| Arc::new(Oneshot {
+    state: ::spin_lock::SpinLock::new(OneshotState { value: None, sender: true, receiver: true,
+                                               waiter: None, watchers: Watchers::new() }),
+  }));
+  (OneshotSender { inner: inner.clone() }, OneshotReceiver { inner: inner })
+}
+
+impl<T, U: SchedulerUnit> OneshotSender<T, U> {
+
+  /// Sends `t`, handing it back if the receiver has been dropped.
+  pub fn send(self, t: T) -> Result<(), T> {
+    let mut state = self.inner.state.lock();
+    if !state.receiver {
+      return Err(t);
+    }
+    state.value = Some(t);
+    let woken = state.woken();
+    drop(state);
+    wake_all::<U>(woken);
+    Ok(())
+  }
+
+}
+
+impl<T, U: SchedulerUnit> Drop for OneshotSender<T, U> {
+
+  fn drop(&mut self) {
+    let mut state = self.inner.state.lock();
+    state.sender = false;
+    let woken = state.woken();
+    drop(state);
+    wake_all::<U>(woken);
+  }
+
+}
+
+impl<T, U: SchedulerUnit> OneshotReceiver<T, U> {
+
+  pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+    let mut state = self.inner.state.lock();
+    match state.value.take() {
+      Some(t) => Ok(t),
+      None if !state.sender => Err(TryRecvError::Disconnected),
+      None => Err(TryRecvError::Empty),
+    }
+  }
+
+  /// Waits for the value, failing if the sender is dropped without sending.
+  pub fn recv(self) -> Result<T, RecvError> {
+    loop {
+      let mut state = self.inner.state.lock();
+      let value = state.value.take();
+      if let Some(t) = value {
+        return Ok(t);
+      }
+      if !state.sender {
+        return Err(RecvError);
+      }
+      debug!("oneshot empty, sleeping");
+      let take = move |me| {
+        state.waiter = Some(me);
+        drop(state);
+      };
+      Thread::<U>::park(BlockedOn::new("oneshot", &*self.inner), &take);
+    }
+  }
+
+}
+
+impl<T, U: SchedulerUnit> Selectable<U> for OneshotReceiver<T, U> {
+
+  fn register(&self, waker: &Arc<Waker<U>>, index: usize) -> bool {
+    let mut state = self.inner.state.lock();
+    if state.value.is_some() || !state.sender {
+      return true;
+    }
+    state.watchers.add(waker, index);
+    false
+  }
+
+  fn unregister(&self, waker: &Arc<Waker<U>>) {
+    self.inner.state.lock().watchers.remove(waker);
+  }
+
+}
+
+impl<T, U: SchedulerUnit> Drop for OneshotReceiver<T, U> {
+
+  fn drop(&mut self) {
+    let mut state = self.inner.state.lock();
+    state.receiver = false;
+    let value = state.value.take();
+    drop(state);
+    drop(value);
+  }
+
+}
+
+unsafe impl<T: Send, U: SchedulerUnit> Send for OneshotSender<T, U> {}
+unsafe impl<T: Send, U: SchedulerUnit> Send for OneshotReceiver<T, U> {}
@@ -0,0 +1,419 @@
+// A SchedulerUnit for periodic real-time threads: it always runs the ready
+// thread whose current job has the earliest absolute deadline.
+//
+// A real-time thread runs in jobs, one per period, calling `next_period` at
+// the end of each. Threads without a period are best-effort and only run
+// when no real-time thread is ready. Times are in clock ticks.
+
+extern crate alloc;
+
+use self::alloc::boxed::Box;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use fringe::OwnedStack;
+use scheduler::{self, Request, ThreadId, ThreadList};
+use time::{self, Clock as ClockTrait};
+use lock;
+use thread;
+
+pub struct Unit;
+impl ::scheduler::SchedulerUnit for Unit {
+  type L = Local;
+  type N = Node;
+  type Q = Queue;
+  type S = OwnedStack;
+  type C = Clock;
+}
+
+// Deadlines need a clock that counts ticks. Hosted, a tick is a
+// microsecond of wall-clock time; otherwise the timer interrupt handler has
+// to call `TickClock::tick`.
+#[cfg(test)]
+pub type Clock = time::ManualClock;
+#[cfg(all(not(test), feature = "hosted"))]
+pub type Clock = time::StdTickClock;
+#[cfg(all(not(test), not(feature = "hosted")))]
+pub type Clock = time::TickClock;
+
+/// The timing of a periodic thread, in ticks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Params {
+  pub period: usize,
+  /// Relative to the start of each period; at most `period`.
+  pub deadline: usize,
+  /// Worst-case execution time of one job, which admission control trusts.
+  pub wcet: usize,
+}
+
+impl Params {
+
+  /// Parameters with the deadline at the end of the period.
+  pub fn new(period: usize, wcet: usize) -> Params {
+    Params { period: period, deadline: period, wcet: wcet }
+  }
+
+  pub fn with_deadline(mut self, deadline: usize) -> Params {
+    self.deadline = deadline;
+    self
+  }
+
+}
+
+// Share of the CPU a thread may need, as a fraction of `ONE`: its execution
+// time over its deadline, which is never after the end of the period.
+// Rounded up, so a set that needs exactly the whole CPU may be turned away.
+// The deadline must not be 0.
+fn density(params: &Params) -> u64 {
+  let window = params.deadline as u64;
+  ((params.wcet as u64) * ONE + window - 1) / window
+}
+
+// Fixed point for densities.
+const ONE: u64 = 1 << 32;
+
+pub struct Local {
+  params: Option<Params>,
+  // Start and absolute deadline of the current job. The deadline of a
+  // best-effort thread is `usize::MAX`.
+  release: usize,
+  deadline: usize,
+  // Whether the current job's miss has been reported.
+  missed: bool,
+  affinity: usize,
+}
+
+impl Default for Local {
+
+  fn default() -> Local {
+    Local { params: None, release: 0, deadline: !0, missed: false, affinity: !0 }
+  }
+
+}
+
+impl Local {
+
+  pub fn params(&self) -> Option<Params> {
+    self.params
+  }
+
+  /// Makes the thread periodic, with its first job released now. Only
+  /// takes effect the next time the thread is queued.
+  pub fn set_params(&mut self, params: Params) {
+    assert!(params.deadline <= params.period, "deadline after the end of the period");
+    self.params = Some(params);
+    self.release = Clock::now();
+    self.deadline = self.release.saturating_add(params.deadline);
+    self.missed = false;
+  }
+
+  /// Absolute deadline of the current job.
+  pub fn deadline(&self) -> usize {
+    self.deadline
+  }
+
+  // Restricts which CPUs a balanced scheduler may move the thread to.
+  pub fn set_affinity(&mut self, mask: usize) {
+    self.affinity = mask;
+  }
+
+}
+
+impl scheduler::Affinity for Local {
+
+  fn affinity(&self) -> usize {
+    self.affinity
+  }
+
+}
+
+/// A deadline that passed before its job finished.
+#[derive(Clone, Copy, Debug)]
+pub struct Miss {
+  pub thread: ThreadId,
+  pub name: Option<&'static str>,
+  pub deadline: usize,
+  pub now: usize,
+}
+
+// The miss handler, as a `fn(Miss)`, or 0.
+static ON_MISS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Has `f` called for each job that misses its deadline, once per job:
+/// when the miss is noticed as the thread is queued, or when the job ends.
+/// It is called by the scheduler or the late thread, so must not block.
+pub fn on_miss(f: fn(Miss)) {
+  ON_MISS.store(f as usize, Ordering::SeqCst);
+}
+
+// Reports a miss if the current job of `t` is late and hasn't been yet.
+fn check_deadline(t: &mut Thread) {
+  let now = Clock::now();
+  if t.local().missed || now <= t.local().deadline {
+    return;
+  }
+  t.local_mut().missed = true;
+  let f = ON_MISS.load(Ordering::SeqCst);
+  if f != 0 {
+    let f: fn(Miss) = unsafe { mem::transmute(f) };
+    f(Miss { thread: t.id(), name: t.name(), deadline: t.local().deadline, now: now });
+  }
+}
+
+/// Returned by `TaskSet::admit` for a thread that would overload the CPU,
+/// or whose deadline is 0 or after the end of its period.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rejected;
+
+/// A thread's share of a `TaskSet`, to give back with `TaskSet::remove`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Admitted {
+  density: u64,
+}
+
+/// Admission control: the parameters of the threads admitted so far, whose
+/// densities (execution time over the shorter of deadline and period) may
+/// add up to at most 1. EDF meets every deadline of such a set; when
+/// deadlines equal periods this is exactly the test utilization <= 1.
+pub struct TaskSet {
+  density: u64,
+}
+
+impl TaskSet {
+
+  pub fn new() -> TaskSet {
+    TaskSet { density: 0 }
+  }
+
+  /// Adds `params` to the set, unless that would overload it.
+  pub fn admit(&mut self, params: Params) -> Result<Admitted, Rejected> {
+    if params.deadline == 0 || params.deadline > params.period {
+      return Err(Rejected);
+    }
+    let admitted = density(&params);
+    if self.density + admitted > ONE {
+      return Err(Rejected);
+    }
+    self.density += admitted;
+    Ok(Admitted { density: admitted })
+  }
+
+  /// Takes out a thread admitted earlier, e.g. once it has finished.
+  pub fn remove(&mut self, admitted: Admitted) {
+    debug_assert!(admitted.density <= self.density, "admitted to another set");
+    self.density = self.density.saturating_sub(admitted.density);
+  }
+
+  /// Share of the CPU admitted so far, in parts per million (rounded up).
+  pub fn utilization_ppm(&self) -> u64 {
+    (self.density * 1_000_000 + ONE - 1) / ONE
+  }
+
+}
+
+/// Whether `tasks` could all be admitted to an empty `TaskSet`.
+pub fn admissible(tasks: &[Params]) -> bool {
+  let mut set = TaskSet::new();
+  tasks.iter().all(|&params| set.admit(params).is_ok())
+}
+
+pub type Node = Box<scheduler::Thread<Unit>>;
+pub type Scheduler = scheduler::Scheduler<Unit>;
+pub type Mutex<T> = lock::Mutex<T, Unit>;
+pub type MutexGuard<'a, T> = lock::MutexGuard<'a, T, Unit>;
+pub type Condvar = lock::Condvar<Unit>;
+pub type Thread = scheduler::Thread<Unit>;
+pub type JoinHandle<T> = thread::JoinHandle<T, Unit>;
+
+/// Spawns a periodic thread from inside a running thread, with its first
+/// job released now. Admission is up to the caller.
+pub fn spawn<F, T>(stack: OwnedStack, params: Params, f: F) -> JoinHandle<T>
+  where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+  let (mut t, handle) = thread::new::<Unit, _, _>(stack, f);
+  t.local_mut().set_params(params);
+  Thread::suspend(Request::Schedule(<Node as scheduler::Node<Unit>>::new(t)));
+  handle
+}
+
+/// Ends the running thread's current job, and waits for the next period
+/// if it hasn't started yet.
+pub fn next_period() {
+  let (release, now) = {
+    let t = Thread::current_mut();
+    check_deadline(t);
+    let local = t.local_mut();
+    let params = local.params.expect("only periodic threads have a next period");
+    local.release = local.release.saturating_add(params.period);
+    local.deadline = local.release.saturating_add(params.deadline);
+    local.missed = false;
+    (local.release, Clock::now())
+  };
+  if release > now {
+    Thread::sleep_until(release);
+  } else {
+    // Late: requeue behind anything due sooner.
+    Thread::suspend(Request::Yield);
+  }
+}
+
+/// Absolute deadline of the running thread's current job.
+pub fn deadline() -> usize {
+  Thread::current().local().deadline()
+}
+
+impl scheduler::Node<Unit> for Node {
+
+  fn new(t: Thread) -> Self {
+    box t
+  }
+
+  fn deref(&self) -> &Thread {
+    &**self
+  }
+
+  fn deref_mut(&mut self) -> &mut Thread {
+    &mut **self
+  }
+
+  fn into_thread(self) -> Thread {
+    *self
+  }
+
+}
+
+/// A ready queue ordered by absolute deadline, first come first served
+/// among equal deadlines. Linked through the threads, so a thread whose
+/// wait times out comes out in constant time.
+pub struct Queue {
+  list: ThreadList<Unit>,
+}
+
+impl ::scheduler::Queue<Unit> for Queue {
+
+  fn new() -> Queue {
+    Queue { list: ThreadList::new() }
+  }
+
+  fn push(&mut self, mut node: Node) {
+    check_deadline(&mut node);
+    let deadline = node.local().deadline;
+    self.list.insert_before_first(node, &|t| t.local().deadline > deadline);
+  }
+
+  fn pop(&mut self) -> Option<Node> {
+    self.list.pop_front()
+  }
+
+  fn front(&self) -> Option<&Node> {
+    self.list.front()
+  }
+
+  fn front_mut(&mut self) -> Option<&mut Node> {
+    self.list.front_mut()
+  }
+
+  fn remove(&mut self, thread: &Thread) -> Option<Node> {
+    self.list.remove(thread)
+  }
+
+  // Takes the thread with the latest deadline that may go.
+  fn steal(&mut self, can_take: &Fn(&Thread) -> bool) -> Option<Node> {
+    let front = match self.list.front() {
+      Some(node) => &**node as *const Thread,
+      None => return None,
+    };
+    self.list.remove_last_where(&|t| t as *const Thread != front && can_take(t))
+  }
+
+  fn for_each(&self, f: &mut FnMut(&Thread)) {
+    self.list.for_each(f)
+  }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+  use super::*;
+  use scheduler::Queue as QueueTrait;
+  use fringe::OwnedStack;
+
+  fn thread<F: FnOnce() + Send + 'static>(params: Params, f: F) -> Thread {
+    let mut t = Thread::new(OwnedStack::new(1024 * 1024), f);
+    t.local_mut().set_params(params);
+    t
+  }
+
+  #[test]
+  fn admission_control() {
+    let mut set = TaskSet::new();
+    assert!(set.admit(Params::new(4, 1)).is_ok());
+    let half = set.admit(Params::new(2, 1)).unwrap();
+    assert_eq!(set.utilization_ppm(), 750_000);
+    assert_eq!(set.admit(Params::new(3, 1)), Err(Rejected));
+    assert!(set.admit(Params::new(4, 1)).is_ok());
+    assert_eq!(set.utilization_ppm(), 1_000_000);
+    set.remove(half);
+    assert_eq!(set.utilization_ppm(), 500_000);
+    // Nonsense is turned away rather than counted.
+    assert_eq!(set.admit(Params::new(0, 0)), Err(Rejected));
+    assert_eq!(set.admit(Params::new(10, 0).with_deadline(0)), Err(Rejected));
+    assert_eq!(set.admit(Params::new(10, 1).with_deadline(20)), Err(Rejected));
+    assert_eq!(set.utilization_ppm(), 500_000);
+    // Shorter deadlines count against the deadline, not the period.
+    assert!(set.admit(Params::new(10, 1).with_deadline(2)).is_ok());
+    assert_eq!(set.utilization_ppm(), 1_000_000);
+    assert!(admissible(&[Params::new(4, 1), Params::new(4, 3)]));
+    assert!(!admissible(&[Params::new(4, 1), Params::new(8, 4).with_deadline(4)]));
+  }
+
+  #[test]
+  fn runs_earliest_deadline_first() {
+    Clock::set(0);
+    let mut q = Queue::new();
+    let order = Arc::new(::std::sync::Mutex::new(vec!()));
+    for &deadline in [30, 10, 20].iter() {
+      let order = order.clone();
+      let params = Params::new(100, 1).with_deadline(deadline);
+      q.push(Box::new(thread(params, move || {
+        order.lock().unwrap().push(deadline);
+      })));
+    }
+    q.push(Box::new(Thread::new(OwnedStack::new(1024 * 1024), || {})));
+
+    Scheduler::new(q).run();
+    assert_eq!(*order.lock().unwrap(), vec!(10, 20, 30));
+  }
+
+  #[test]
+  fn reports_deadline_misses() {
+    static MISSED: AtomicUsize = ATOMIC_USIZE_INIT;
+    fn missed(miss: Miss) {
+      assert_eq!(miss.name, Some("control"));
+      assert_eq!((miss.deadline, miss.now), (15, 18));
+      MISSED.fetch_add(1, Ordering::SeqCst);
+    }
+    on_miss(missed);
+
+    Clock::set(0);
+    let mut q = Queue::new();
+    let releases = Arc::new(::std::sync::Mutex::new(vec!()));
+    let saved = releases.clone();
+    q.push(Box::new(thread(Params::new(10, 2).with_deadline(5), move || {
+      for job in 0..4 {
+        releases.lock().unwrap().push(Clock::now());
+        // The second job overruns.
+        Clock::advance(if job == 1 { 8 } else { 2 });
+        next_period();
+      }
+    }).with_name("control")));
+
+    Scheduler::new(q).run();
+    assert_eq!(MISSED.load(Ordering::SeqCst), 1);
+    assert_eq!(*saved.lock().unwrap(), vec!(0, 10, 20, 30));
+  }
+
+}
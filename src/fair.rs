@@ -0,0 +1,551 @@
+// A SchedulerUnit that shares the CPU fairly between threads by the time
+// they actually ran, weighted by their nice values, like Linux's CFS.
+//
+// Each thread has a virtual runtime that grows as it runs, more slowly the
+// higher its weight, and the ready thread with the least goes next. Time is
+// measured in clock ticks from when the scheduler resumes a thread to when
+// it makes its next request, so preemption is what stops a thread that
+// never yields from running on.
+
+extern crate alloc;
+
+use self::alloc::boxed::Box;
+use core::cmp::max;
+use core::mem;
+use core::ptr;
+
+use fringe::OwnedStack;
+use scheduler::{self, Queue as QueueTrait, Request};
+use time::{self, Clock as ClockTrait};
+use lock;
+use thread;
+
+pub struct Unit;
+impl ::scheduler::SchedulerUnit for Unit {
+  type L = Local;
+  type N = Node;
+  type Q = Queue;
+  type S = OwnedStack;
+  type C = Clock;
+}
+
+// Runtime needs a clock that counts ticks. Hosted, a tick is a microsecond
+// of wall-clock time; otherwise the timer interrupt handler has to call
+// `TickClock::tick`, or every slice measures 0 and weights do nothing.
+#[cfg(test)]
+pub type Clock = time::ManualClock;
+#[cfg(all(not(test), feature = "hosted"))]
+pub type Clock = time::StdTickClock;
+#[cfg(all(not(test), not(feature = "hosted")))]
+pub type Clock = time::TickClock;
+
+// Weights for nice values -20 to 19, each about 1.25 times the next, as in
+// Linux. A thread at nice 0 weighs 1024.
+const WEIGHTS: [u64; 40] = [
+  88761, 71755, 56483, 46273, 36291,
+  29154, 23254, 18705, 14949, 11916,
+  9548, 7620, 6100, 4904, 3906,
+  3121, 2501, 1991, 1586, 1277,
+  1024, 820, 655, 526, 423,
+  335, 272, 215, 172, 137,
+  110, 87, 70, 56, 45,
+  36, 29, 23, 18, 15,
+];
+
+// A tick of running time adds this divided by the weight to the virtual
+// runtime, so that it counts in 1024ths of a tick at nice 0.
+const SCALE: u64 = 1 << 20;
+
+pub const MIN_NICE: i32 = -20;
+pub const MAX_NICE: i32 = 19;
+
+pub struct Local {
+  nice: i32,
+  weight: u64,
+  vruntime: u64,
+  // When it was queued, to go first come first served among equals.
+  seq: u64,
+  links: Links,
+  affinity: usize,
+}
+
+impl Default for Local {
+
+  fn default() -> Local {
+    Local { nice: 0, weight: 1024, vruntime: 0, seq: 0, links: Links::none(), affinity: !0 }
+  }
+
+}
+
+impl Local {
+
+  pub fn nice(&self) -> i32 {
+    self.nice
+  }
+
+  pub fn set_nice(&mut self, nice: i32) {
+    assert!(nice >= MIN_NICE && nice <= MAX_NICE, "nice value out of range");
+    self.nice = nice;
+    self.weight = WEIGHTS[(nice - MIN_NICE) as usize];
+  }
+
+  /// Virtual runtime, in 1024ths of a tick at nice 0.
+  pub fn vruntime(&self) -> u64 {
+    self.vruntime
+  }
+
+  // Restricts which CPUs a balanced scheduler may move the thread to.
+  pub fn set_affinity(&mut self, mask: usize) {
+    self.affinity = mask;
+  }
+
+}
+
+impl scheduler::Affinity for Local {
+
+  fn affinity(&self) -> usize {
+    self.affinity
+  }
+
+}
+
+// Links in the pairing heap of a `Queue`. A thread owns its first child and
+// its next sibling, and points back at its previous sibling, or its parent
+// if it is the first child.
+struct Links {
+  // The id of the queue, or 0.
+  queue: u64,
+  child: Option<Node>,
+  next: Option<Node>,
+  prev: *mut Thread,
+}
+
+// Only followed by whoever has the queue.
+unsafe impl Send for Links {}
+
+impl Links {
+
+  fn none() -> Links {
+    Links { queue: 0, child: None, next: None, prev: ptr::null_mut() }
+  }
+
+}
+
+pub type Node = Box<scheduler::Thread<Unit>>;
+pub type Scheduler = scheduler::Scheduler<Unit>;
+pub type Mutex<T> = lock::Mutex<T, Unit>;
+pub type MutexGuard<'a, T> = lock::MutexGuard<'a, T, Unit>;
+pub type Condvar = lock::Condvar<Unit>;
+pub type Thread = scheduler::Thread<Unit>;
+pub type JoinHandle<T> = thread::JoinHandle<T, Unit>;
+
+/// Spawns `f` on a new thread at `nice` from inside a running thread.
+pub fn spawn<F, T>(stack: OwnedStack, nice: i32, f: F) -> JoinHandle<T>
+  where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+  let (mut t, handle) = thread::new::<Unit, _, _>(stack, f);
+  t.local_mut().set_nice(nice);
+  Thread::suspend(Request::Schedule(<Node as scheduler::Node<Unit>>::new(t)));
+  handle
+}
+
+/// Nice value of the running thread.
+pub fn nice() -> i32 {
+  Thread::current().local().nice()
+}
+
+/// Changes the nice value of the running thread, which weighs the time it
+/// runs from now on.
+pub fn set_nice(nice: i32) {
+  Thread::current_mut().local_mut().set_nice(nice);
+}
+
+/// Virtual runtime of the running thread, up to when it was last resumed.
+pub fn vruntime() -> u64 {
+  Thread::current().local().vruntime()
+}
+
+impl scheduler::Node<Unit> for Node {
+
+  fn new(t: Thread) -> Self {
+    box t
+  }
+
+  fn deref(&self) -> &Thread {
+    &**self
+  }
+
+  fn deref_mut(&mut self) -> &mut Thread {
+    &mut **self
+  }
+
+  fn into_thread(self) -> Thread {
+    *self
+  }
+
+}
+
+/// A ready queue ordered by virtual runtime, kept in a pairing heap linked
+/// through the threads, so it never allocates.
+///
+/// The running thread is kept out of the heap while its virtual runtime
+/// grows, so that it stays at the front until it makes way. A thread queued
+/// with less virtual runtime than the queue's least so far is moved up to
+/// it, so that time spent asleep doesn't become a claim on the CPU later.
+pub struct Queue {
+  id: u64,
+  current: Option<Node>,
+  root: Option<Node>,
+  seq: u64,
+  // Never goes down, though the least virtual runtime queued may.
+  min_vruntime: u64,
+}
+
+unsafe impl Send for Queue {}
+unsafe impl Sync for Queue {}
+
+impl Queue {
+
+  // Adds a node to the heap.
+  fn insert(&mut self, mut node: Node) {
+    node.local_mut().links.queue = self.id;
+    node.local_mut().links.prev = ptr::null_mut();
+    self.root = Some(match self.root.take() {
+      Some(root) => meld(root, node),
+      None => node,
+    });
+  }
+
+  fn pop_root(&mut self) -> Option<Node> {
+    match self.root.take() {
+      Some(mut root) => {
+        self.root = merge_pairs(root.local_mut().links.child.take());
+        Some(detached(root))
+      }
+      None => None,
+    }
+  }
+
+  // Takes `thread` out from under the root of the heap.
+  unsafe fn unlink(&mut self, thread: *const Thread) -> Node {
+    let prev = (*thread).local().links.prev;
+    let link = if link_to(&(*prev).local().links.child) == thread {
+      &mut (*prev).local_mut().links.child
+    } else {
+      &mut (*prev).local_mut().links.next
+    };
+    let mut node = link.take().unwrap();
+    *link = node.local_mut().links.next.take();
+    if let Some(ref mut next) = *link {
+      next.local_mut().links.prev = prev;
+    }
+    if let Some(children) = merge_pairs(node.local_mut().links.child.take()) {
+      let root = self.root.take().unwrap();
+      self.root = Some(meld(root, children));
+    }
+    detached(node)
+  }
+
+  // The first thread in the heap for which `f` holds, visiting parents
+  // before children, or null.
+  fn find(&self, f: &mut FnMut(&Thread) -> bool) -> *const Thread {
+    let mut thread = link_to(&self.root);
+    unsafe {
+      while !thread.is_null() {
+        if f(&*thread) {
+          return thread;
+        }
+        if (*thread).local().links.child.is_some() {
+          thread = link_to(&(*thread).local().links.child);
+          continue;
+        }
+        // Up to the nearest thread with a next sibling.
+        while !thread.is_null() && (*thread).local().links.next.is_none() {
+          thread = parent(thread);
+        }
+        if !thread.is_null() {
+          thread = link_to(&(*thread).local().links.next);
+        }
+      }
+    }
+    ptr::null()
+  }
+
+}
+
+impl ::scheduler::Queue<Unit> for Queue {
+
+  fn new() -> Queue {
+    Queue {
+      id: scheduler::next_list_id(),
+      current: None,
+      root: None,
+      seq: 0,
+      min_vruntime: 0,
+    }
+  }
+
+  fn push(&mut self, mut node: Node) {
+    debug_assert!(node.local().links.queue == 0, "thread is already in a queue");
+    {
+      let local = node.local_mut();
+      local.vruntime = max(local.vruntime, self.min_vruntime);
+      local.seq = self.seq;
+    }
+    self.seq += 1;
+    self.insert(node);
+  }
+
+  fn pop(&mut self) -> Option<Node> {
+    match self.current.take() {
+      Some(node) => Some(detached(node)),
+      None => self.pop_root(),
+    }
+  }
+
+  fn front(&self) -> Option<&Node> {
+    self.current.as_ref().or(self.root.as_ref())
+  }
+
+  fn front_mut(&mut self) -> Option<&mut Node> {
+    if self.current.is_some() {
+      self.current.as_mut()
+    } else {
+      self.root.as_mut()
+    }
+  }
+
+  fn remove(&mut self, thread: &Thread) -> Option<Node> {
+    if thread.local().links.queue != self.id {
+      return None;
+    }
+    let target = thread as *const Thread;
+    if link_to(&self.current) == target {
+      self.pop()
+    } else if link_to(&self.root) == target {
+      self.pop_root()
+    } else {
+      Some(unsafe { self.unlink(target) })
+    }
+  }
+
+  fn steal(&mut self, can_take: &Fn(&Thread) -> bool) -> Option<Node> {
+    let front = self.front().map_or(ptr::null(), |node| &**node as *const Thread);
+    let thread = self.find(&mut |t: &Thread| t as *const Thread != front && can_take(t));
+    if thread.is_null() {
+      return None;
+    }
+    self.remove(unsafe { &*thread })
+  }
+
+  fn for_each(&self, f: &mut FnMut(&Thread)) {
+    if let Some(ref node) = self.current {
+      f(&**node);
+    }
+    self.find(&mut |t: &Thread| {
+      f(t);
+      false
+    });
+  }
+
+  fn ran(&mut self, since: usize) {
+    if self.current.is_none() {
+      let mut node = match self.pop_root() {
+        Some(node) => node,
+        None => return,
+      };
+      node.local_mut().links.queue = self.id;
+      self.current = Some(node);
+    }
+    let ran = Clock::now().saturating_sub(since) as u64;
+    let current = {
+      let local = self.current.as_mut().unwrap().local_mut();
+      local.vruntime += ran * SCALE / local.weight;
+      local.vruntime
+    };
+    let least = match self.root {
+      Some(ref root) if root.local().vruntime < current => root.local().vruntime,
+      _ => current,
+    };
+    self.min_vruntime = max(self.min_vruntime, least);
+  }
+
+}
+
+impl Drop for Queue {
+
+  // One node at a time, rather than each dropping its children.
+  fn drop(&mut self) {
+    while let Some(node) = self.pop() {
+      drop(node);
+    }
+  }
+
+}
+
+fn before(a: &Thread, b: &Thread) -> bool {
+  (a.local().vruntime, a.local().seq) < (b.local().vruntime, b.local().seq)
+}
+
+fn link_to(link: &Option<Node>) -> *const Thread {
+  link.as_ref().map_or(ptr::null(), |node| &**node as *const Thread)
+}
+
+// A node taken out of the heap, with its links cleared.
+fn detached(mut node: Node) -> Node {
+  node.local_mut().links = Links::none();
+  node
+}
+
+// The parent of `thread` in the heap, or null for the root.
+unsafe fn parent(mut thread: *const Thread) -> *const Thread {
+  loop {
+    let prev = (*thread).local().links.prev as *const Thread;
+    if prev.is_null() || link_to(&(*prev).local().links.child) == thread {
+      return prev;
+    }
+    thread = prev;
+  }
+}
+
+// Melds two heaps, given by their roots, into one.
+fn meld(mut a: Node, mut b: Node) -> Node {
+  if before(&b, &a) {
+    mem::swap(&mut a, &mut b);
+  }
+  let parent: *mut Thread = &mut *a;
+  let child: *mut Thread = &mut *b;
+  {
+    let links = &mut b.local_mut().links;
+    links.prev = parent;
+    links.next = a.local_mut().links.child.take();
+    if let Some(ref mut next) = links.next {
+      next.local_mut().links.prev = child;
+    }
+  }
+  a.local_mut().links.child = Some(b);
+  a
+}
+
+// Melds a list of siblings into one heap: in pairs left to right, then the
+// pairs right to left.
+fn merge_pairs(mut siblings: Option<Node>) -> Option<Node> {
+  // Melded pairs, last first, linked through `next`.
+  let mut pairs: Option<Node> = None;
+  while let Some(mut a) = siblings.take() {
+    siblings = match a.local_mut().links.next.take() {
+      Some(mut b) => {
+        let rest = b.local_mut().links.next.take();
+        a = meld(a, b);
+        rest
+      }
+      None => None,
+    };
+    a.local_mut().links.next = pairs.take();
+    pairs = Some(a);
+  }
+  let mut root = match pairs {
+    Some(root) => root,
+    None => return None,
+  };
+  let mut rest = root.local_mut().links.next.take();
+  while let Some(mut pair) = rest.take() {
+    rest = pair.local_mut().links.next.take();
+    root = meld(root, pair);
+  }
+  root.local_mut().links.prev = ptr::null_mut();
+  Some(root)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::boxed::Box;
+  use std::sync::Arc;
+
+  use super::*;
+  use scheduler::{Queue as QueueTrait, Request};
+  use time::{Clock as ClockTrait, ManualClock};
+  use fringe::OwnedStack;
+
+  fn thread<F: FnOnce() + Send + 'static>(nice: i32, f: F) -> Thread {
+    let mut t = Thread::new(OwnedStack::new(1024 * 1024), f);
+    t.local_mut().set_nice(nice);
+    t
+  }
+
+  #[test]
+  fn heap_order_and_removal() {
+    let mut q = Queue::new();
+    let mut threads = vec!();
+    for (&name, &vruntime) in ["a", "b", "c", "d", "e", "f", "g"].iter().zip([5, 1, 4, 1, 3, 9, 2].iter()) {
+      let mut t = Box::new(thread(0, || {}).with_name(name));
+      t.local_mut().vruntime = vruntime;
+      threads.push(&*t as *const Thread);
+      q.push(t);
+    }
+    let mut count = 0;
+    q.for_each(&mut |_| count += 1);
+    assert_eq!(count, 7);
+
+    let mut nodes = vec!();
+    for &i in [2, 5].iter() {
+      nodes.push(q.remove(unsafe { &*threads[i] }).unwrap());
+      assert!(q.remove(unsafe { &*threads[i] }).is_none());
+    }
+    let stolen = q.steal(&|_| true).unwrap();
+    let stolen_name = stolen.name().unwrap();
+    assert!(stolen_name != "b");
+    nodes.push(stolen);
+
+    let mut order = vec!();
+    while let Some(node) = q.pop() {
+      order.push(node.name().unwrap());
+      nodes.push(node);
+    }
+    let expected: ::std::vec::Vec<_> =
+      ["b", "d", "g", "e", "a"].iter().cloned().filter(|&name| name != stolen_name).collect();
+    assert_eq!(order, expected);
+
+    for node in nodes {
+      q.push(node);
+    }
+    Scheduler::new(q).run();
+  }
+
+  #[test]
+  fn weights_share_the_cpu() {
+    ManualClock::set(0);
+    let mut q = Queue::new();
+    let runs = Arc::new(::std::sync::Mutex::new([0, 0]));
+    for (i, &nice) in [0, 5].iter().enumerate() {
+      let runs = runs.clone();
+      q.push(Box::new(thread(nice, move || {
+        while ManualClock::now() < 40 {
+          runs.lock().unwrap()[i] += 1;
+          ManualClock::advance(1);
+          Thread::suspend(Request::Yield);
+        }
+      })));
+    }
+    Scheduler::new(q).run();
+    // Nice 5 weighs about a third of nice 0.
+    assert_eq!(*runs.lock().unwrap(), [30, 10]);
+  }
+
+  #[test]
+  fn sleeper_catches_up_to_the_rest() {
+    ManualClock::set(0);
+    let mut q = Queue::new();
+    q.push(Box::new(thread(0, || {
+      Thread::sleep(20);
+      assert_eq!(vruntime(), 20 * 1024);
+    })));
+    q.push(Box::new(thread(0, || {
+      while ManualClock::now() < 30 {
+        ManualClock::advance(1);
+        Thread::suspend(Request::Yield);
+      }
+    })));
+    Scheduler::new(q).run();
+  }
+
+}
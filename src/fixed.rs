@@ -0,0 +1,335 @@
+// A scheduler unit that never allocates, for targets without a heap.
+//
+// Thread control blocks and stacks come out of byte arrays the user declares
+// as statics and hands over at boot with `give_slots` and `give_stacks`. A
+// finished thread's slot and stack go back for the next thread to use.
+//
+// A slot holds just the thread: the run queue is the scheduler's
+// `ThreadList`, whose links live in the thread itself, so queueing and
+// taking a thread out (say when its wait times out) doesn't allocate either.
+//
+// Only threads made with `Thread::new` and scheduled with
+// `Request::Schedule(Node::new(..))` stay off the heap: `thread::spawn` and
+// `JoinHandle` share the result through an `Arc`, and need the `alloc`
+// feature.
+
+use core::mem::{self, align_of, size_of};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+
+use fringe::Stack;
+
+use scheduler;
+use time;
+
+pub struct Unit;
+impl scheduler::SchedulerUnit for Unit {
+  type L = ();
+  type N = Node;
+  type Q = Queue;
+  type S = StaticStack;
+  type C = Clock;
+}
+
+#[cfg(test)]
+pub type Clock = time::ManualClock;
+#[cfg(all(not(test), feature = "hosted"))]
+pub type Clock = time::StdClock;
+#[cfg(all(not(test), not(feature = "hosted")))]
+pub type Clock = time::TickClock;
+
+pub type Thread = scheduler::Thread<Unit>;
+/// The run queue, linked through the threads in their slots.
+pub type Queue = scheduler::ThreadList<Unit>;
+pub type Scheduler = scheduler::Scheduler<Unit>;
+pub type Cpu = scheduler::Cpu<Unit>;
+
+type Arch = ::arch::Arch<Thread>;
+
+// A free block, at the start of its own memory.
+struct Free {
+  next: *mut Free,
+  len: usize,
+}
+
+// A list of free blocks, behind a spin lock that can be made in a static.
+struct Slab {
+  locked: AtomicBool,
+  head: AtomicUsize,
+}
+
+static SLOTS: Slab = Slab { locked: ATOMIC_BOOL_INIT, head: ATOMIC_USIZE_INIT };
+static STACKS: Slab = Slab { locked: ATOMIC_BOOL_INIT, head: ATOMIC_USIZE_INIT };
+
+impl Slab {
+
+  // Calls `f` on the head of the list with the lock held. Threads aren't
+  // preempted meanwhile, or one could spin on a lock its own CPU holds.
+  fn with_head<T, F: FnOnce(&mut *mut Free) -> T>(&self, f: F) -> T {
+    let _guard = unsafe { Arch::no_preempt() };
+    while self.locked.compare_and_swap(false, true, Ordering::Acquire) {}
+    let mut head = self.head.load(Ordering::Relaxed) as *mut Free;
+    let result = f(&mut head);
+    self.head.store(head as usize, Ordering::Relaxed);
+    self.locked.store(false, Ordering::Release);
+    result
+  }
+
+  // Splits `memory` into blocks of `block` bytes aligned to `align`, and
+  // frees them all. Returns how many there were.
+  fn give(&self, memory: &'static mut [u8], block: usize, align: usize) -> usize {
+    let block = (block + align - 1) & !(align - 1);
+    let start = memory.as_mut_ptr() as usize;
+    let end = start + memory.len();
+    let mut at = (start + align - 1) & !(align - 1);
+    let mut count = 0;
+    while at + block <= end {
+      self.put(at as *mut u8, block);
+      at += block;
+      count += 1;
+    }
+    count
+  }
+
+  // Takes the first free block of at least `len` bytes.
+  fn take(&self, len: usize) -> Option<(*mut u8, usize)> {
+    self.with_head(|head| unsafe {
+      let mut link: *mut *mut Free = head;
+      while !(*link).is_null() {
+        let block = *link;
+        if (*block).len >= len {
+          *link = (*block).next;
+          return Some((block as *mut u8, (*block).len));
+        }
+        link = &mut (*block).next;
+      }
+      None
+    })
+  }
+
+  // Number of free blocks.
+  fn len(&self) -> usize {
+    self.with_head(|head| unsafe {
+      let mut block = *head;
+      let mut len = 0;
+      while !block.is_null() {
+        len += 1;
+        block = (*block).next;
+      }
+      len
+    })
+  }
+
+  fn put(&self, block: *mut u8, len: usize) {
+    self.with_head(|head| unsafe {
+      ptr::write(block as *mut Free, Free { next: *head, len: len });
+      *head = block as *mut Free;
+    })
+  }
+
+}
+
+/// Gives the memory to hold thread control blocks, returning how many fit.
+/// May be called more than once.
+pub fn give_slots(memory: &'static mut [u8]) -> usize {
+  SLOTS.give(memory, size_of::<Thread>(), align_of::<Thread>())
+}
+
+/// Gives the memory for stacks of `size` bytes each, returning how many
+/// fit. May be called more than once, with different sizes.
+pub fn give_stacks(memory: &'static mut [u8], size: usize) -> usize {
+  STACKS.give(memory, size, 16)
+}
+
+/// Number of thread slots not in use.
+pub fn free_slots() -> usize {
+  SLOTS.len()
+}
+
+/// Number of stacks not in use, of any size.
+pub fn free_stacks() -> usize {
+  STACKS.len()
+}
+
+/// A stack taken from the memory given with `give_stacks`, which it goes
+/// back to when dropped.
+pub struct StaticStack {
+  ptr: *mut u8,
+  len: usize,
+}
+
+unsafe impl Send for StaticStack {}
+
+impl StaticStack {
+
+  /// Takes a free stack of at least `size` bytes, if there is one.
+  pub fn take(size: usize) -> Option<StaticStack> {
+    STACKS.take(size).map(|(ptr, len)| StaticStack { ptr: ptr, len: len })
+  }
+
+}
+
+impl Stack for StaticStack {
+
+  fn base(&self) -> *mut u8 {
+    unsafe { self.ptr.offset(self.len as isize) }
+  }
+
+  fn limit(&self) -> *mut u8 {
+    self.ptr
+  }
+
+}
+
+impl Drop for StaticStack {
+
+  fn drop(&mut self) {
+    STACKS.put(self.ptr, self.len);
+  }
+
+}
+
+/// Owns a thread control block in memory given with `give_slots`, like a
+/// `Box` would.
+pub struct Node(*mut Thread);
+
+unsafe impl Send for Node {}
+
+impl Node {
+
+  /// Puts `t` in a free slot, or hands it back if there is none.
+  pub fn try_new(t: Thread) -> Result<Node, Thread> {
+    match SLOTS.take(size_of::<Thread>()) {
+      Some((slot, _)) => {
+        let slot = slot as *mut Thread;
+        unsafe { ptr::write(slot, t) };
+        Ok(Node(slot))
+      }
+      None => Err(t),
+    }
+  }
+
+}
+
+impl scheduler::Node<Unit> for Node {
+
+  // Panics when out of slots; `try_new` doesn't.
+  fn new(t: Thread) -> Node {
+    match Node::try_new(t) {
+      Ok(node) => node,
+      Err(_) => panic!("out of thread slots"),
+    }
+  }
+
+  fn deref(&self) -> &Thread {
+    unsafe { &*self.0 }
+  }
+
+  fn deref_mut(&mut self) -> &mut Thread {
+    unsafe { &mut *self.0 }
+  }
+
+  fn into_thread(self) -> Thread {
+    let slot = self.0;
+    mem::forget(self);
+    unsafe {
+      let thread = ptr::read(slot);
+      SLOTS.put(slot as *mut u8, size_of::<Thread>());
+      thread
+    }
+  }
+
+}
+
+impl Drop for Node {
+
+  fn drop(&mut self) {
+    unsafe {
+      ptr::drop_in_place(self.0);
+      SLOTS.put(self.0 as *mut u8, size_of::<Thread>());
+    }
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+  use std::mem::size_of;
+  use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+  use super::*;
+  use super::SLOTS;
+  use scheduler::{Node as NodeTrait, Queue as QueueTrait, Request};
+
+  // A byte array may start anywhere, so there is room to spare for aligning
+  // the blocks.
+  static mut SLOT_MEMORY: [u8; 16 * 1024] = [0; 16 * 1024];
+  static mut STACK_MEMORY: [u8; 4 * 64 * 1024 + 15] = [0; 4 * 64 * 1024 + 15];
+
+  #[test]
+  fn runs_without_allocating() {
+    static RAN: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    let slots = give_slots(unsafe { &mut SLOT_MEMORY });
+    assert!(slots >= 4);
+    assert_eq!(give_stacks(unsafe { &mut STACK_MEMORY }, 64 * 1024), 4);
+
+    fn work() {
+      RAN.fetch_add(1, Ordering::SeqCst);
+      Thread::suspend(Request::Yield);
+      RAN.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // Every thread lives in a slot and on a stack from the slabs.
+    let mut q = Queue::new();
+    for _ in 0..2 {
+      q.push(Node::new(Thread::new(StaticStack::take(64 * 1024).unwrap(), work)));
+    }
+    q.push(Node::new(Thread::new(StaticStack::take(64 * 1024).unwrap(), move || {
+      // The first two threads' slots and stacks aren't free yet.
+      let t = Thread::new(StaticStack::take(64 * 1024).unwrap(), work);
+      assert!(StaticStack::take(64 * 1024).is_none());
+      assert_eq!(free_stacks(), 0);
+      assert_eq!(free_slots(), slots - 3);
+      Thread::suspend(Request::Schedule(Node::new(t)));
+    })));
+
+    Scheduler::new(q).run();
+    assert_eq!(free_slots(), slots);
+    assert_eq!(free_stacks(), 4);
+
+    // Taking a thread out of the middle of a queue needs no more room.
+    let mut q = Queue::new();
+    for _ in 0..3 {
+      q.push(Node::new(Thread::new(StaticStack::take(64 * 1024).unwrap(), work)));
+    }
+    let mut ids = [None; 3];
+    let mut i = 0;
+    q.for_each(&mut |t| { ids[i] = Some(t.id()); i += 1; });
+    let middle = q.remove_where(&|t| Some(t.id()) == ids[1]).unwrap();
+    assert!(q.remove(middle.deref()).is_none());
+    assert_eq!(free_slots(), slots - 3);
+    q.push(middle);
+    let mut order = [None; 3];
+    let mut i = 0;
+    q.for_each(&mut |t| { order[i] = Some(t.id()); i += 1; });
+    assert_eq!(order, [ids[0], ids[2], ids[1]]);
+    Scheduler::new(q).run();
+    assert_eq!(RAN.load(Ordering::SeqCst), 12);
+    assert_eq!(free_slots(), slots);
+    assert_eq!(free_stacks(), 4);
+
+    // With every slot taken a thread can't be put anywhere.
+    let taken: ::std::vec::Vec<_> = (0..slots).map(|_| SLOTS.take(size_of::<Thread>()).unwrap()).collect();
+    let t = Thread::new(StaticStack::take(64 * 1024).unwrap(), work);
+    let t = Node::try_new(t).err().expect("got a slot that was taken");
+    for (slot, len) in taken {
+      SLOTS.put(slot, len);
+    }
+    let mut q = Queue::new();
+    q.push(Node::try_new(t).ok().expect("slots went back"));
+    Scheduler::new(q).run();
+    assert_eq!(RAN.load(Ordering::SeqCst), 14);
+  }
+
+}
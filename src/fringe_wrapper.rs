@@ -30,7 +30,7 @@ impl<'a, I, O, S: Stack> Group<'a, I, O, S> where I: Send + 'a, O: Send + 'a, S:
               debug!("inner yielder at 0x{:x}", *(yielder_usize as *const usize));
               debug!("bar {}", 1);
               yielder.suspend(uninitialized());
-              f();
+              run(f);
           });
 
           forget(gen.resume(uninitialized()));
@@ -44,10 +44,48 @@ impl<'a, I, O, S: Stack> Group<'a, I, O, S> where I: Send + 'a, O: Send + 'a, S:
         self.generator.resume(i)        
     }
     
+    // Only once the function has returned.
+    pub fn into_stack(self) -> S {
+        self.generator.unwrap()
+    }
+
     // Unsafe because needs to be called in the right thread...
     pub unsafe fn suspend(&self, o: O) -> I {
         //info!("suspending to yielder at 0x{:x}", self.yielder as *const Yielder<_, _> as usize);
         self.yielder.suspend(o)
     }
 
-}
\ No newline at end of file
+}
+
+// A panic must not unwind out of the generator, so in hosted builds it is
+// caught here and the thread just finishes. Locks it held are poisoned by
+// their guards on the way out, which know the thread is unwinding from the
+// flag the panic hook sets.
+#[cfg(feature = "hosted")]
+fn run<F: FnOnce()>(f: F) {
+    use std::boxed::Box;
+    use std::panic::{self, PanicInfo};
+    use std::sync::{Once, ONCE_INIT};
+
+    // Kept out of the hook so that installing it doesn't allocate: the
+    // first thread may be running where there is no heap (see `fixed`).
+    static HOOK: Once = ONCE_INIT;
+    static mut PREVIOUS: Option<Box<Fn(&PanicInfo) + Sync + Send + 'static>> = None;
+    HOOK.call_once(|| unsafe {
+        PREVIOUS = Some(panic::take_hook());
+        panic::set_hook(Box::new(|info| {
+            if ::arch::Arch::<()>::in_thread() {
+                ::arch::Arch::<()>::set_panicking(true);
+            }
+            if let Some(ref previous) = PREVIOUS {
+                previous(info);
+            }
+        }));
+    });
+    let _ = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(f));
+}
+
+#[cfg(not(feature = "hosted"))]
+fn run<F: FnOnce()>(f: F) {
+    f()
+}
@@ -0,0 +1,96 @@
+// Waking threads from interrupt handlers.
+//
+// An interrupt handler can't switch threads or take a spin lock that the code
+// it interrupted may hold. `IrqWaker::wake` only touches atomics: it marks the
+// waker pending and pushes it onto a lock-free list on the CPU of the waiting
+// thread, which the scheduler drains between switches.
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use scheduler::{Cpu, Thread, SchedulerUnit, Node, BlockedOn};
+
+/// An event a single thread can wait for and an interrupt handler can signal.
+///
+/// Wakeups don't queue up: any number of `wake` calls before the next
+/// `wait` make it return once.
+///
+/// The scheduler must be running `forever`, or it will return as soon as
+/// the waiting thread is the only one left.
+pub struct IrqWaker<U: SchedulerUnit> {
+  pending: AtomicBool,
+  // Whether the waker is on a CPU's deferred list.
+  queued: AtomicBool,
+  next: AtomicPtr<IrqWaker<U>>,
+  // The CPU the waiting thread is parked on, if any.
+  cpu: AtomicPtr<Cpu<U>>,
+  waiter: ::spin_lock::SpinLock<Option<U::N>>,
+}
+
+unsafe impl<U: SchedulerUnit> Send for IrqWaker<U> {}
+unsafe impl<U: SchedulerUnit> Sync for IrqWaker<U> {}
+
+impl<U: SchedulerUnit> IrqWaker<U> {
+
+  pub fn new() -> IrqWaker<U> {
+    IrqWaker {
+      pending: AtomicBool::new(false),
+      queued: AtomicBool::new(false),
+      next: AtomicPtr::new(ptr::null_mut()),
+      cpu: AtomicPtr::new(ptr::null_mut()),
+      waiter: ::spin_lock::SpinLock::new(None),
+    }
+  }
+
+  /// Makes the waiting thread runnable, or the next `wait` return at once.
+  /// Safe to call from an interrupt handler.
+  pub fn wake(&'static self) {
+    self.pending.store(true, Ordering::SeqCst);
+    let cpu = self.cpu.load(Ordering::SeqCst);
+    // A thread that isn't parked yet will see `pending` before it is.
+    if cpu.is_null() || self.queued.swap(true, Ordering::SeqCst) {
+      return;
+    }
+    unsafe { (*cpu).defer(self) };
+  }
+
+  /// Waits for `wake` to be called, unless it was since the last `wait`.
+  pub fn wait(&self) {
+    while !self.pending.swap(false, Ordering::SeqCst) {
+      debug!("waiting for interrupt");
+      let take = |me: U::N| {
+        let mut waiter = self.waiter.lock();
+        let cpu = me.deref().parked_on().unwrap();
+        self.cpu.store(cpu as *const Cpu<U> as *mut Cpu<U>, Ordering::SeqCst);
+        if self.pending.load(Ordering::SeqCst) {
+          // Woken while we were parking, maybe too early to find us.
+          self.cpu.store(ptr::null_mut(), Ordering::SeqCst);
+          drop(waiter);
+          cpu.schedule(me);
+        } else {
+          *waiter = Some(me);
+        }
+      };
+      Thread::<U>::park(BlockedOn::new("irq", self), &take);
+    }
+  }
+
+  // Called by the scheduler draining its deferred list: returns the next
+  // waker on the list and the thread to wake, if it is still parked.
+  pub(crate) fn take(&self) -> (*mut IrqWaker<U>, Option<U::N>) {
+    let next = self.next.load(Ordering::SeqCst);
+    self.queued.store(false, Ordering::SeqCst);
+    let mut waiter = self.waiter.lock();
+    let node = waiter.take();
+    if node.is_some() {
+      self.cpu.store(ptr::null_mut(), Ordering::SeqCst);
+    }
+    (next, node)
+  }
+
+  // Called by whoever pushes the waker onto a CPU's deferred list.
+  pub(crate) fn set_next(&self, next: *mut IrqWaker<U>) {
+    self.next.store(next, Ordering::SeqCst);
+  }
+
+}
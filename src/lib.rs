@@ -13,13 +13,15 @@
 #[macro_use]
 extern crate std;
 
+// Some tests run under a preemption timer, so printing, which allocates,
+// must not be preempted.
 #[cfg(test)]
 macro_rules! debug {
     ($fmt:expr) => {
-      println!(concat!("DEBUG: ", $fmt));
+      ::preempt::no_preempt(|| println!(concat!("DEBUG: ", $fmt)));
     };
     ($fmt:expr, $($arg:tt)*) => {
-      println!(concat!("DEBUG: ", $fmt), $($arg)*);
+      ::preempt::no_preempt(|| println!(concat!("DEBUG: ", $fmt), $($arg)*));
     }
 }
 
@@ -33,15 +35,48 @@ macro_rules! debug {
 
 extern crate fringe;
 extern crate spin;
+#[cfg(feature = "hosted")]
+extern crate libc;
 
 mod arch;
 
+mod spin_lock;
+
 mod fringe_wrapper;
 
 pub mod scheduler;
 
+pub mod time;
+
 pub mod lock;
 
+#[cfg(feature = "alloc")]
+pub mod thread;
+
+pub mod stack;
+
+#[cfg(feature = "alloc")]
+pub mod channel;
+
+#[cfg(feature = "alloc")]
+#[macro_use]
+pub mod select;
+
+pub mod preempt;
+
+pub mod irq;
+
+#[cfg(feature = "alloc")]
 mod linked_list;
+#[cfg(feature = "alloc")]
 pub mod basic;
+#[cfg(feature = "alloc")]
+pub mod priority;
+#[cfg(feature = "alloc")]
+pub mod edf;
+#[cfg(feature = "alloc")]
+pub mod rate_monotonic;
+#[cfg(feature = "alloc")]
+pub mod fair;
+pub mod fixed;
 pub mod poison;
@@ -206,6 +206,46 @@ impl<T> LinkedList<T> {
             }
         })
     }
+
+    /// Unlink `node`, which must be in this list, and return it
+    unsafe fn unlink_node(&mut self, mut node: Rawlink<Node<T>>) -> Box<Node<T>> {
+        let node = node.resolve().unwrap();
+        let mut prev = node.prev.take();
+        let mut next = node.next.take();
+        match next {
+            Some(ref mut next) => next.prev = prev,
+            None => self.list_tail = prev,
+        }
+        let own = match prev.resolve() {
+            None => mem::replace(&mut self.list_head, next),
+            Some(prev) => mem::replace(&mut prev.next, next),
+        };
+        self.length -= 1;
+        own.unwrap()
+    }
+
+    /// Remove the first Node whose value matches `pred` and return it, or
+    /// None if there is no such Node
+    pub fn remove_node_where<F>(&mut self, mut pred: F) -> Option<Box<Node<T>>>
+        where F: FnMut(&T) -> bool {
+        let mut cur = match self.list_head {
+            Some(ref mut head) => Rawlink::some(&mut **head),
+            None => return None,
+        };
+        loop {
+            let node = match cur.resolve() {
+                None => return None,
+                Some(node) => node,
+            };
+            if pred(&node.value) {
+                return Some(unsafe { self.unlink_node(cur) });
+            }
+            cur = match node.next {
+                Some(ref mut next) => Rawlink::some(&mut **next),
+                None => Rawlink::none(),
+            };
+        }
+    }
 }
 
 impl<T> Default for LinkedList<T> {
@@ -992,6 +1032,20 @@ mod tests {
         assert_eq!(m.into_iter().collect::<Vec<_>>(), [-2,0,1,2,3,4,5,6,7,8,9,0,1]);
     }
 
+    #[test]
+    fn test_remove_node_where() {
+        let mut m = list_from(&[1,2,3,4]);
+        assert_eq!(m.remove_node_where(|&x| x == 1).map(|n| n.value), Some(1));
+        check_links(&m);
+        assert_eq!(m.remove_node_where(|&x| x == 4).map(|n| n.value), Some(4));
+        check_links(&m);
+        assert_eq!(m.remove_node_where(|&x| x == 7).map(|n| n.value), None);
+        m.push_back(5);
+        assert_eq!(m.remove_node_where(|&x| x == 3).map(|n| n.value), Some(3));
+        check_links(&m);
+        assert_eq!(m.into_iter().collect::<Vec<_>>(), [2,5]);
+    }
+
     #[test]
     fn test_send() {
         let n = list_from(&[1,2,3]);
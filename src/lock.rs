@@ -2,25 +2,116 @@ use core::ops::Deref;
 use core::ops::DerefMut;
 use core::marker::PhantomData;
 use core::cell::UnsafeCell;
+use core::mem::swap;
+use core::cmp::max;
+use core::ptr;
 
-use scheduler::{Scheduler, Thread, Request, SchedulerUnit, Queue};
+use scheduler::{Scheduler, Thread, Request, SchedulerUnit, Queue, Node, Instant, Duration,
+                BlockedOn};
+use time::Clock;
 
-use ::poison::{LockResult, TryLockError, TryLockResult};
+use ::poison::{self, LockResult, TryLockError, TryLockResult};
+#[cfg(feature = "alloc")]
+use select::Watchers;
+
+// Without `alloc` there is no `select`, so nothing ever watches a lock.
+#[cfg(not(feature = "alloc"))]
+struct Watchers<U: SchedulerUnit>(PhantomData<U>);
+
+#[cfg(not(feature = "alloc"))]
+impl<U: SchedulerUnit> Watchers<U> {
+
+  fn new() -> Watchers<U> {
+    Watchers(PhantomData)
+  }
+
+  fn fire_all(&mut self, _: &mut U::Q) {}
+
+}
 
 pub struct Mutex<T, U: SchedulerUnit> {
-  queue_lock: ::spin::Mutex<(U::Q, bool)>,
+  queue_lock: WaitLock<U>,
+  inherit: Option<fn(Event<U>) -> bool>,
+  poison: poison::Flag,
   data: UnsafeCell<T>,
   p: PhantomData<U>
 }
 
+/// The part of a `Mutex` that doesn't depend on the data it protects.
+pub struct MutexState<U: SchedulerUnit> {
+  queue: U::Q,
+  taken: bool,
+  // The rest is only kept up to date by inheriting mutexes.
+  owner: *mut Thread<U>,
+  next_held: *const WaitLock<U>,
+  // Threads selecting on the mutex, fired when it is unlocked.
+  watchers: Watchers<U>,
+}
+
+// The raw pointers are only followed while holding the lock they came from.
+unsafe impl<U: SchedulerUnit> Send for MutexState<U> {}
+
+type WaitLock<U> = ::spin_lock::SpinLock<MutexState<U>>;
+
+/// Per-thread bookkeeping for inheriting mutexes: the mutex the thread is
+/// blocked on and the list of mutexes it holds.
+pub struct Links<U: SchedulerUnit> {
+  blocked_on: *const WaitLock<U>,
+  held: *const WaitLock<U>,
+}
+
+unsafe impl<U: SchedulerUnit> Send for Links<U> {}
+
+impl<U: SchedulerUnit> Default for Links<U> {
+
+  fn default() -> Links<U> {
+    Links { blocked_on: ptr::null(), held: ptr::null() }
+  }
+
+}
+
+/// Implemented by thread locals of units with priorities, so that a mutex
+/// created with `Mutex::new_inheriting` can lend the priority of its highest
+/// waiter to the thread holding it.
+pub trait Inherit<U: SchedulerUnit> {
+
+  // Priority the thread runs at, including anything lent to it.
+  fn priority(&self) -> usize;
+
+  // Priority the thread runs at when nothing is lent to it.
+  fn base_priority(&self) -> usize;
+
+  // Sets the priority the thread runs at, never below its base priority.
+  fn lend_priority(&mut self, priority: usize);
+
+  fn links(&mut self) -> &mut Links<U>;
+}
+
+// What an inheriting mutex tells the priority bookkeeping about.
+enum Event<'a, U: SchedulerUnit + 'a> {
+  // The current thread took the lock, which is still held.
+  Acquired(&'a WaitLock<U>, &'a mut MutexState<U>),
+  // The current thread is about to block on the lock.
+  Blocked(&'a WaitLock<U>),
+  // The current thread stopped waiting for the lock.
+  Woken,
+  // The current thread gave up waiting for the lock and is out of its queue.
+  GaveUp(&'a WaitLock<U>),
+  // The current thread gave up the lock.
+  Released(&'a WaitLock<U>),
+}
+
 pub struct MutexGuard<'a, T:'a, U: SchedulerUnit> {
-    lock: &'a Mutex<T, U>
+    lock: &'a Mutex<T, U>,
+    poison: poison::Guard,
 }
 
 impl<'a, T: 'a, U: SchedulerUnit> MutexGuard<'a, T, U> {
 
-  fn new(lock: &'a Mutex<T, U>) -> MutexGuard<'a, T, U> {
-    MutexGuard { lock: lock }
+  fn new(lock: &'a Mutex<T, U>) -> LockResult<MutexGuard<'a, T, U>> {
+    poison::map_result(lock.poison.borrow(), |guard| {
+      MutexGuard { lock: lock, poison: guard }
+    })
   }
 
 }
@@ -43,61 +134,190 @@ impl<'a, T:'a, U: SchedulerUnit> DerefMut for MutexGuard<'a, T, U> {
 impl<'a, T:'a, U: SchedulerUnit> Drop for MutexGuard<'a, T, U> {
 
   fn drop(&mut self) {
+    self.lock.poison.done(&self.poison);
     self.lock.unlock();
   }
 
 }
 
+fn new_state<U: SchedulerUnit>() -> WaitLock<U> {
+  ::spin_lock::SpinLock::new(MutexState { queue: U::Q::new(),
+                                  taken: false,
+                                  owner: ptr::null_mut(),
+                                  next_held: ptr::null(),
+                                  watchers: Watchers::new() })
+}
+
+impl<T, U: SchedulerUnit> Mutex<T, U> where U::L: Inherit<U> {
+
+  /// Creates a mutex that lends the priority of its highest waiter to the
+  /// thread holding it, and through that thread to the holders of any
+  /// inheriting mutexes it is blocked on in turn. The holder goes back to
+  /// its own priority when the guard is dropped.
+  pub fn new_inheriting(data: T) -> Mutex<T, U> {
+    Mutex { queue_lock: new_state(),
+            inherit: Some(inherit::<U>),
+            poison: poison::Flag::new(),
+            data: UnsafeCell::new(data),
+            p: PhantomData::<U>,
+    }
+  }
+
+}
+
 impl<T, U: SchedulerUnit> Mutex<T, U> {
 
   pub fn new(data: T) -> Mutex<T, U> {
-    Mutex { queue_lock: ::spin::Mutex::new((U::Q::new(), false)),
+    Mutex { queue_lock: new_state(),
+            inherit: None,
+            poison: poison::Flag::new(),
             data: UnsafeCell::new(data),
             p: PhantomData::<U>,
     }
   }
 
+  fn acquired(&self, state: &mut MutexState<U>) {
+    state.taken = true;
+    if let Some(inherit) = self.inherit {
+      inherit(Event::Acquired(&self.queue_lock, state));
+    }
+  }
+
+  // Lends our priority to the holder before blocking. This can let other
+  // threads run, so the caller has to check the lock again afterwards.
+  fn lend(&self) {
+    if let Some(inherit) = self.inherit {
+      inherit(Event::Blocked(&self.queue_lock));
+    }
+  }
+
+  fn woken(&self) {
+    if let Some(inherit) = self.inherit {
+      inherit(Event::Woken);
+    }
+  }
+
+  // Takes back from the holder what we lent it, unless another waiter
+  // still needs it to run that high.
+  fn gave_up(&self) {
+    if let Some(inherit) = self.inherit {
+      inherit(Event::GaveUp(&self.queue_lock));
+    }
+  }
+
   pub fn try_lock(&self) -> TryLockResult<MutexGuard<T, U>> {
     let mut l = self.queue_lock.lock();
-    let &mut (_, ref mut taken) = l.deref_mut();
-    if *taken {
+    if l.taken {
       Err(TryLockError::WouldBlock)
     } else {
-      *taken = true;
-      Ok(MutexGuard::new(self))
+      self.acquired(l.deref_mut());
+      drop(l);
+      Ok(try!(MutexGuard::new(self)))
     }
   }
 
   pub fn lock(&self) -> LockResult<MutexGuard<T, U>> {
+    let mut lent = false;
     loop {
       let mut l = self.queue_lock.lock();
-      match l.deref_mut() {
-        &mut (_, ref mut taken) => {
-          if !*taken {
-            *taken = true;
-            break;
-          }
-        }
+      if !l.taken {
+        self.acquired(l.deref_mut());
+        break;
+      }
+      if self.inherit.is_some() && !lent {
+        // Lending can switch threads, so it's done with the queue unlocked.
+        drop(l);
+        self.lend();
+        lent = true;
+        continue;
       }
       debug!("didn't get lock, sleeping");
       let take = move |me| {
-        match l.deref_mut() {
-          &mut (ref mut queue, _) => queue.push(me)
-        }
+        l.queue.push(me);
+        drop(l);
+      };
+      Thread::<U>::park(BlockedOn::new("mutex", self), &take);
+      lent = false;
+    }
+    self.woken();
+    MutexGuard::new(self)
+  }
+
+  // Like `lock`, but gives up with `WouldBlock` once `duration` has passed.
+  pub fn try_lock_for(&self, duration: Duration<U>) -> TryLockResult<MutexGuard<T, U>> {
+    self.try_lock_until(U::C::after(U::C::now(), duration))
+  }
+
+  pub fn try_lock_until(&self, deadline: Instant<U>) -> TryLockResult<MutexGuard<T, U>> {
+    let mut lent = false;
+    loop {
+      let mut l = self.queue_lock.lock();
+      if !l.taken {
+        self.acquired(l.deref_mut());
+        break;
+      }
+      if U::C::now() >= deadline {
+        drop(l);
+        self.gave_up();
+        return Err(TryLockError::WouldBlock);
+      }
+      if self.inherit.is_some() && !lent {
+        drop(l);
+        self.lend();
+        lent = true;
+        continue;
+      }
+      debug!("didn't get lock, sleeping until deadline");
+      let take = move |me| {
+        l.queue.push(me);
         drop(l);
       };
-      Thread::<U>::suspend(Request::make_schedule(&take));
+      let cancel = |me: &Thread<U>| self.queue_lock.lock().queue.remove(me);
+      if Thread::<U>::park_until(BlockedOn::new("mutex", self), deadline, &take, &cancel) {
+        self.gave_up();
+        return Err(TryLockError::WouldBlock);
+      }
+      lent = false;
     }
-    Ok(MutexGuard::new(self))
+    self.woken();
+    Ok(try!(MutexGuard::new(self)))
+  }
+
+  /// Whether a thread panicked while holding this mutex. Only hosted builds
+  /// catch panics, so this is never true otherwise.
+  pub fn is_poisoned(&self) -> bool {
+    self.poison.get()
+  }
+
+  /// Makes the mutex usable again after a thread panicked holding it.
+  pub fn clear_poison(&self) {
+    self.poison.clear();
   }
 
   fn unlock(&self) {
     let mut l = self.queue_lock.lock();
-    let &mut (ref mut queue, ref mut taken) = l.deref_mut();
-    *taken = false;
-    if let Some(node) = queue.pop() {
+    l.taken = false;
+    l.owner = ptr::null_mut();
+    let node = l.queue.pop();
+    let mut selecting = U::Q::new();
+    l.watchers.fire_all(&mut selecting);
+    // Spin locks are never held across a switch, so the scheduler is free
+    // to take them when cancelling a timed-out waiter.
+    drop(l);
+    let requeue = match self.inherit {
+      Some(inherit) => inherit(Event::Released(&self.queue_lock)),
+      None => false,
+    };
+    if let Some(node) = node {
+      Thread::<U>::suspend(Request::Schedule(node));
+    }
+    while let Some(node) = selecting.pop() {
       Thread::<U>::suspend(Request::Schedule(node));
     }
+    // Only drop back to our own priority once the waiter has been woken up.
+    if requeue {
+      Thread::<U>::suspend(Request::make_requeue(Thread::<U>::current_mut()));
+    }
   }
 }
 
@@ -105,14 +325,205 @@ impl<T, U: SchedulerUnit> Mutex<T, U> {
 unsafe impl<T: Send, U: SchedulerUnit> Send for Mutex<T, U> { }
 unsafe impl<T: Send, U: SchedulerUnit> Sync for Mutex<T, U> { }
 
+// Priority bookkeeping for inheriting mutexes. Returns whether the current
+// thread's priority went down and it has to be requeued.
+fn inherit<U: SchedulerUnit>(event: Event<U>) -> bool where U::L: Inherit<U> {
+  let me = Thread::<U>::current_mut();
+  match event {
+    Event::Acquired(lock, state) => {
+      state.owner = me as *mut Thread<U>;
+      let links = me.local_mut().links();
+      state.next_held = links.held;
+      links.held = lock;
+      false
+    },
+    Event::Blocked(lock) => {
+      me.local_mut().links().blocked_on = lock;
+      let priority = me.local().priority();
+      unsafe { lend::<U>(lock, priority) };
+      false
+    },
+    Event::Woken => {
+      me.local_mut().links().blocked_on = ptr::null();
+      false
+    },
+    Event::GaveUp(lock) => {
+      me.local_mut().links().blocked_on = ptr::null();
+      unsafe { unlend::<U>(lock) };
+      false
+    },
+    Event::Released(lock) => {
+      unsafe {
+        unlink_held::<U>(me.local_mut().links(), lock);
+        let old = me.local().priority();
+        let priority = inherited::<U>(me, None).unwrap();
+        me.local_mut().lend_priority(priority);
+        priority < old
+      }
+    },
+  }
+}
+
+// The priority `thread` should run at: its own, or that of the highest
+// waiter on an inheriting mutex it holds. A caller that has one of those
+// locked already passes it in with its state. The others are then only
+// tried, since another thread may be going through them the other way
+// round, and None means the caller has to let go and start over.
+unsafe fn inherited<U: SchedulerUnit>(thread: *mut Thread<U>,
+                                      locked: Option<(*const WaitLock<U>, &MutexState<U>)>)
+                                      -> Option<usize> where U::L: Inherit<U> {
+  let mut priority = (*thread).local().base_priority();
+  let mut held = (*thread).local_mut().links().held;
+  while !held.is_null() {
+    let (waiter, next) = match locked {
+      Some((lock, state)) if lock == held => (top_waiter(state), state.next_held),
+      Some(_) => match (*held).try_lock() {
+        Some(state) => (top_waiter(&state), state.next_held),
+        None => return None,
+      },
+      None => {
+        let state = (*held).lock();
+        (top_waiter(&state), state.next_held)
+      }
+    };
+    if let Some(waiter) = waiter {
+      priority = max(priority, waiter);
+    }
+    held = next;
+  }
+  Some(priority)
+}
+
+// Priority of the thread first in line for a lock, if any.
+fn top_waiter<U: SchedulerUnit>(state: &MutexState<U>) -> Option<usize> where U::L: Inherit<U> {
+  state.queue.front().map(|waiter| Node::deref(waiter).local().priority())
+}
+
+// Walks the chain of holders starting at `lock`, raising each to
+// `priority` and moving it up whichever queue it is waiting in.
+//
+// A holder is only looked at with the lock it holds locked, so that it
+// can't let go of it and finish meanwhile. The last one may have by the
+// time the scheduler gets the requeue, which is why the scheduler only
+// follows it once it has found it in its run queue.
+unsafe fn lend<U: SchedulerUnit>(mut lock: *const WaitLock<U>, priority: usize) where U::L: Inherit<U> {
+  loop {
+    let next = {
+      let state = (*lock).lock();
+      let owner = state.owner;
+      if owner.is_null() || (*owner).local().priority() >= priority {
+        return;
+      }
+      debug!("lending priority {} to 0x{:x}", priority, owner as usize);
+      raise_or_lower::<U>(owner, priority)
+    };
+    match next {
+      Ok(next) => lock = next,
+      Err(owner) => {
+        Thread::<U>::suspend(Request::make_requeue(owner));
+        return;
+      }
+    }
+  }
+}
+
+// Walks the chain of holders starting at `lock` after a waiter left it,
+// dropping each back to what its remaining waiters lend it. Holders are
+// only looked at with their lock locked, as in `lend`.
+unsafe fn unlend<U: SchedulerUnit>(mut lock: *const WaitLock<U>) where U::L: Inherit<U> {
+  loop {
+    let next = {
+      let state = (*lock).lock();
+      let owner = state.owner;
+      if owner.is_null() {
+        return;
+      }
+      let priority = match inherited::<U>(owner, Some((lock, &*state))) {
+        Some(priority) => priority,
+        None => continue,
+      };
+      if priority >= (*owner).local().priority() {
+        return;
+      }
+      debug!("taking priority of 0x{:x} back to {}", owner as usize, priority);
+      raise_or_lower::<U>(owner, priority)
+    };
+    match next {
+      Ok(next) => lock = next,
+      Err(owner) => {
+        Thread::<U>::suspend(Request::make_requeue(owner));
+        return;
+      }
+    }
+  }
+}
+
+// Sets the priority of `owner`, whose lock the caller has locked. If it is
+// waiting on another inheriting mutex, moves it to its new place in that
+// queue and returns the mutex, for the caller to carry on with its holder.
+// Otherwise it is runnable, or blocked on something that doesn't inherit,
+// and is handed back for the caller to requeue once it has let go.
+unsafe fn raise_or_lower<U: SchedulerUnit>(owner: *mut Thread<U>, priority: usize)
+                                           -> Result<*const WaitLock<U>, *mut Thread<U>>
+                                           where U::L: Inherit<U> {
+  let next = (*owner).local_mut().links().blocked_on;
+  if next.is_null() {
+    (*owner).local_mut().lend_priority(priority);
+    return Err(owner);
+  }
+  let mut state = (*next).lock();
+  (*owner).local_mut().lend_priority(priority);
+  if let Some(node) = state.queue.remove(&*owner) {
+    state.queue.push(node);
+  }
+  Ok(next)
+}
+
+unsafe fn unlink_held<U: SchedulerUnit>(links: &mut Links<U>, lock: *const WaitLock<U>) {
+  let after = (*lock).lock().next_held;
+  if links.held == lock {
+    links.held = after;
+    return;
+  }
+  let mut held = links.held;
+  while !held.is_null() {
+    let mut state = (*held).lock();
+    if state.next_held == lock {
+      state.next_held = after;
+      return;
+    }
+    held = state.next_held;
+  }
+}
+
 pub struct Condvar<U: SchedulerUnit> {
-  sleepers: ::spin::Mutex<(U::Q)>
+  sleepers: ::spin_lock::SpinLock<CondvarState<U>>
+}
+
+struct CondvarState<U: SchedulerUnit> {
+  queue: U::Q,
+  // Threads selecting on the condvar, each of which takes a notification
+  // when no thread is waiting.
+  watchers: Watchers<U>,
+}
+
+/// Whether a timed wait on a `Condvar` returned because its timeout passed.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+
+  pub fn timed_out(&self) -> bool {
+    self.0
+  }
+
 }
 
 impl<U: SchedulerUnit> Condvar<U> {
 
   pub fn new() -> Condvar<U> {
-    Condvar { sleepers: ::spin::Mutex::new(U::Q::new()) }
+    Condvar { sleepers: ::spin_lock::SpinLock::new(CondvarState { queue: U::Q::new(),
+                                                                  watchers: Watchers::new() }) }
   }
 
   pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T, U>) -> LockResult<MutexGuard<'a, T, U>> {
@@ -121,25 +532,78 @@ impl<U: SchedulerUnit> Condvar<U> {
     let mutex = guard.lock;
     let take = move |me: U::N| {
       debug!("adding a sleeper");
-      sleepers.push(me);
+      sleepers.queue.push(me);
       drop(sleepers);
       drop(guard);
     };
-    Thread::<U>::suspend(Request::make_schedule(&take));
+    Thread::<U>::park(BlockedOn::new("condvar", self), &take);
     mutex.lock()
   }
 
+  pub fn wait_timeout<'a, T>(&self, guard: MutexGuard<'a, T, U>, duration: Duration<U>)
+                             -> LockResult<(MutexGuard<'a, T, U>, WaitTimeoutResult)> {
+    self.wait_timeout_until(guard, U::C::after(U::C::now(), duration))
+  }
+
+  pub fn wait_timeout_until<'a, T>(&self, guard: MutexGuard<'a, T, U>, deadline: Instant<U>)
+                                   -> LockResult<(MutexGuard<'a, T, U>, WaitTimeoutResult)> {
+    debug!("in wait_timeout");
+    let mut sleepers = self.sleepers.lock();
+    let mutex = guard.lock;
+    let take = move |me: U::N| {
+      debug!("adding a timed sleeper");
+      sleepers.queue.push(me);
+      drop(sleepers);
+      drop(guard);
+    };
+    let cancel = |me: &Thread<U>| self.sleepers.lock().queue.remove(me);
+    let timed_out = Thread::<U>::park_until(BlockedOn::new("condvar", self), deadline, &take, &cancel);
+    poison::map_result(mutex.lock(), |guard| (guard, WaitTimeoutResult(timed_out)))
+  }
+
+  // Waits while `condition` holds, for at most `duration` in total. The
+  // result only reports a timeout if `condition` still held at the end.
+  pub fn wait_timeout_while<'a, T, F>(&self, mut guard: MutexGuard<'a, T, U>,
+                                      duration: Duration<U>, mut condition: F)
+                                      -> LockResult<(MutexGuard<'a, T, U>, WaitTimeoutResult)>
+                                      where F: FnMut(&mut T) -> bool {
+    let deadline = U::C::after(U::C::now(), duration);
+    while condition(&mut *guard) {
+      let (g, result) = try!(self.wait_timeout_until(guard, deadline));
+      guard = g;
+      if result.timed_out() {
+        let timed_out = condition(&mut *guard);
+        return Ok((guard, WaitTimeoutResult(timed_out)));
+      }
+    }
+    Ok((guard, WaitTimeoutResult(false)))
+  }
+
   pub fn notify_one(&self) {
     debug!("notifying 1");
-    if let Some(node) = self.sleepers.lock().pop() {
+    let mut woken = U::Q::new();
+    let mut sleepers = self.sleepers.lock();
+    match sleepers.queue.pop() {
+      Some(node) => woken.push(node),
+      // A selector may go on to finish on another of its sources, so every
+      // one of them is told rather than having the notification lost. The
+      // rest see a spurious wakeup.
+      None => sleepers.watchers.fire_all(&mut woken),
+    }
+    drop(sleepers);
+    while let Some(node) = woken.pop() {
       debug!("waking a sleeper");
       Thread::<U>::suspend(Request::Schedule(node));
     }
   }
 
   pub fn notify_all(&self) {
+    let mut woken = U::Q::new();
     let mut sleepers = self.sleepers.lock();
-    while let Some(node) = sleepers.pop() {
+    swap(&mut sleepers.queue, &mut woken);
+    sleepers.watchers.fire_all(&mut woken);
+    drop(sleepers);
+    while let Some(node) = woken.pop() {
       Thread::<U>::suspend(Request::Schedule(node));
     }
   }
@@ -147,11 +611,239 @@ impl<U: SchedulerUnit> Condvar<U> {
 }
 
 
+/// A counting semaphore.
+///
+/// Threads that can't get the permits they ask for park on a `U::Q` wait
+/// queue. Releasing wakes one single-permit waiter per permit handed back,
+/// or every waiter while someone is waiting for several permits at once.
+/// Woken threads check the count again, so a released permit may go to a
+/// thread that didn't have to wait.
+pub struct Semaphore<U: SchedulerUnit> {
+  state: ::spin_lock::SpinLock<SemaphoreState<U>>,
+}
+
+struct SemaphoreState<U: SchedulerUnit> {
+  permits: usize,
+  queue: U::Q,
+  // Parked threads waiting for more than one permit.
+  waiting_many: usize,
+  // Threads selecting on the semaphore, fired when permits are released.
+  watchers: Watchers<U>,
+}
+
+/// RAII structure used to release the permits acquired from a semaphore
+/// when dropped.
+#[must_use]
+pub struct SemaphoreGuard<'a, U: SchedulerUnit + 'a> {
+  semaphore: &'a Semaphore<U>,
+  permits: usize,
+}
+
+impl<U: SchedulerUnit> Semaphore<U> {
+
+  pub fn new(permits: usize) -> Semaphore<U> {
+    Semaphore { state: ::spin_lock::SpinLock::new(SemaphoreState {
+                         permits: permits,
+                         queue: U::Q::new(),
+                         waiting_many: 0,
+                         watchers: Watchers::new(),
+                       }) }
+  }
+
+  pub fn available_permits(&self) -> usize {
+    self.state.lock().permits
+  }
+
+  pub fn try_acquire(&self) -> Option<SemaphoreGuard<U>> {
+    self.try_acquire_many(1)
+  }
+
+  pub fn try_acquire_many(&self, permits: usize) -> Option<SemaphoreGuard<U>> {
+    let mut state = self.state.lock();
+    if state.permits >= permits {
+      state.permits -= permits;
+      Some(SemaphoreGuard { semaphore: self, permits: permits })
+    } else {
+      None
+    }
+  }
+
+  pub fn acquire(&self) -> SemaphoreGuard<U> {
+    self.acquire_many(1)
+  }
+
+  pub fn acquire_many(&self, permits: usize) -> SemaphoreGuard<U> {
+    let mut parked = false;
+    loop {
+      let mut state = self.state.lock();
+      if parked && permits > 1 {
+        state.waiting_many -= 1;
+      }
+      if state.permits >= permits {
+        state.permits -= permits;
+        break;
+      }
+      debug!("not enough permits, sleeping");
+      if permits > 1 {
+        state.waiting_many += 1;
+      }
+      let take = move |me| {
+        state.queue.push(me);
+        drop(state);
+      };
+      Thread::<U>::park(BlockedOn::new("semaphore", self), &take);
+      parked = true;
+    }
+    SemaphoreGuard { semaphore: self, permits: permits }
+  }
+
+  /// Adds `permits` permits, e.g. ones given up with `SemaphoreGuard::forget`.
+  pub fn release(&self, permits: usize) {
+    let mut woken = U::Q::new();
+    let mut state = self.state.lock();
+    state.permits += permits;
+    if state.waiting_many > 0 {
+      swap(&mut state.queue, &mut woken);
+    } else {
+      for _ in 0..permits {
+        match state.queue.pop() {
+          Some(node) => woken.push(node),
+          None => break,
+        }
+      }
+    }
+    state.watchers.fire_all(&mut woken);
+    drop(state);
+    while let Some(node) = woken.pop() {
+      Thread::<U>::suspend(Request::Schedule(node));
+    }
+  }
+
+}
+
+unsafe impl<U: SchedulerUnit> Send for Semaphore<U> {}
+unsafe impl<U: SchedulerUnit> Sync for Semaphore<U> {}
+
+impl<'a, U: SchedulerUnit> SemaphoreGuard<'a, U> {
+
+  /// Gives up the permits without returning them to the semaphore.
+  pub fn forget(self) {
+    ::core::mem::forget(self)
+  }
+
+}
+
+impl<'a, U: SchedulerUnit> Drop for SemaphoreGuard<'a, U> {
+
+  fn drop(&mut self) {
+    self.semaphore.release(self.permits);
+  }
+
+}
+
+// Selecting on a mutex or semaphore waits until it may be free. Selecting on
+// a condvar waits for a notification, which goes to a waiting thread first.
+#[cfg(feature = "alloc")]
+mod selectable {
+
+  extern crate alloc;
+
+  use self::alloc::arc::Arc;
+
+  use scheduler::SchedulerUnit;
+  use select::{Selectable, Waker};
+
+  use super::{Condvar, Mutex, Semaphore};
+
+  impl<T, U: SchedulerUnit> Selectable<U> for Mutex<T, U> {
+
+    fn register(&self, waker: &Arc<Waker<U>>, index: usize) -> bool {
+      let mut l = self.queue_lock.lock();
+      if !l.taken {
+        return true;
+      }
+      l.watchers.add(waker, index);
+      false
+    }
+
+    fn unregister(&self, waker: &Arc<Waker<U>>) {
+      self.queue_lock.lock().watchers.remove(waker);
+    }
+
+  }
+
+  impl<U: SchedulerUnit> Selectable<U> for Semaphore<U> {
+
+    fn register(&self, waker: &Arc<Waker<U>>, index: usize) -> bool {
+      let mut state = self.state.lock();
+      if state.permits > 0 {
+        return true;
+      }
+      state.watchers.add(waker, index);
+      false
+    }
+
+    fn unregister(&self, waker: &Arc<Waker<U>>) {
+      self.state.lock().watchers.remove(waker);
+    }
+
+  }
+
+  impl<U: SchedulerUnit> Selectable<U> for Condvar<U> {
+
+    fn register(&self, waker: &Arc<Waker<U>>, index: usize) -> bool {
+      self.sleepers.lock().watchers.add(waker, index);
+      false
+    }
+
+    fn unregister(&self, waker: &Arc<Waker<U>>) {
+      self.sleepers.lock().watchers.remove(waker);
+    }
+
+  }
+
+}
+
+/// A scheduler-aware reader-writer lock.
+///
+/// Blocked readers and writers are parked on separate `U::Q` wait queues.
+/// The lock prefers writers: once a writer is waiting, new readers queue up
+/// behind it instead of joining the readers already inside, so a steady
+/// stream of readers cannot starve a writer. When a writer releases the lock
+/// it is handed to the next waiting writer if there is one, otherwise to all
+/// of the waiting readers at once. Ownership is handed off before the woken
+/// threads are scheduled, so they never have to race for it again.
 pub struct RwLock<T: ?Sized, U: SchedulerUnit> {
+  state: ::spin_lock::SpinLock<RwState<U>>,
   p: PhantomData<U>,
   __data: UnsafeCell<T>,
 }
 
+struct RwState<U: SchedulerUnit> {
+  readers: usize,
+  writer: bool,
+  read_queue: U::Q,
+  write_queue: U::Q,
+  waiting_writers: usize,
+}
+
+impl<U: SchedulerUnit> RwState<U> {
+
+  fn can_read(&self) -> bool {
+    !self.writer && self.waiting_writers == 0
+  }
+
+  fn can_write(&self) -> bool {
+    !self.writer && self.readers == 0
+  }
+
+}
+
+unsafe impl<T: ?Sized + Send + Sync, U: SchedulerUnit> Send for RwLock<T, U> {}
+unsafe impl<T: ?Sized + Send + Sync, U: SchedulerUnit> Sync for RwLock<T, U> {}
+
+/// RAII structure used to release the shared read access of a lock when
+/// dropped.
 #[must_use]
 pub struct RwLockReadGuard<'a, T: ?Sized + 'a, U: SchedulerUnit> {
   __lock: &'a RwLock<T, U>,
@@ -170,3 +862,157 @@ pub struct RwLockWriteGuard<'a, T: ?Sized + 'a, U: SchedulerUnit> {
 
 impl<'a, T: ?Sized, U: SchedulerUnit> !Send for RwLockWriteGuard<'a, T, U> {}
 
+impl<T, U: SchedulerUnit> RwLock<T, U> {
+
+  pub fn new(data: T) -> RwLock<T, U> {
+    RwLock { state: ::spin_lock::SpinLock::new(RwState {
+                      readers: 0,
+                      writer: false,
+                      read_queue: U::Q::new(),
+                      write_queue: U::Q::new(),
+                      waiting_writers: 0,
+                    }),
+             p: PhantomData::<U>,
+             __data: UnsafeCell::new(data),
+    }
+  }
+
+}
+
+impl<T: ?Sized, U: SchedulerUnit> RwLock<T, U> {
+
+  pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<T, U>> {
+    let mut state = self.state.lock();
+    if state.can_read() {
+      state.readers += 1;
+      Ok(RwLockReadGuard { __lock: self })
+    } else {
+      Err(TryLockError::WouldBlock)
+    }
+  }
+
+  pub fn try_write(&self) -> TryLockResult<RwLockWriteGuard<T, U>> {
+    let mut state = self.state.lock();
+    if state.can_write() {
+      state.writer = true;
+      Ok(RwLockWriteGuard { __lock: self })
+    } else {
+      Err(TryLockError::WouldBlock)
+    }
+  }
+
+  pub fn read(&self) -> LockResult<RwLockReadGuard<T, U>> {
+    let mut state = self.state.lock();
+    if state.can_read() {
+      state.readers += 1;
+      return Ok(RwLockReadGuard { __lock: self });
+    }
+    debug!("didn't get read lock, sleeping");
+    let take = move |me| {
+      state.read_queue.push(me);
+      drop(state);
+    };
+    // The writer that wakes us has already counted us as a reader.
+    Thread::<U>::park(BlockedOn::new("rwlock", self), &take);
+    Ok(RwLockReadGuard { __lock: self })
+  }
+
+  pub fn write(&self) -> LockResult<RwLockWriteGuard<T, U>> {
+    let mut state = self.state.lock();
+    if state.can_write() {
+      state.writer = true;
+      return Ok(RwLockWriteGuard { __lock: self });
+    }
+    debug!("didn't get write lock, sleeping");
+    state.waiting_writers += 1;
+    let take = move |me| {
+      state.write_queue.push(me);
+      drop(state);
+    };
+    // Whoever wakes us has already marked the lock as written.
+    Thread::<U>::park(BlockedOn::new("rwlock", self), &take);
+    Ok(RwLockWriteGuard { __lock: self })
+  }
+
+  fn read_unlock(&self) {
+    let mut state = self.state.lock();
+    state.readers -= 1;
+    if state.readers != 0 {
+      return;
+    }
+    let writer = state.write_queue.pop();
+    if writer.is_some() {
+      state.waiting_writers -= 1;
+      state.writer = true;
+    }
+    drop(state);
+    if let Some(node) = writer {
+      Thread::<U>::suspend(Request::Schedule(node));
+    }
+  }
+
+  fn write_unlock(&self) {
+    let mut state = self.state.lock();
+    state.writer = false;
+    let writer = state.write_queue.pop();
+    if let Some(node) = writer {
+      state.waiting_writers -= 1;
+      state.writer = true;
+      drop(state);
+      Thread::<U>::suspend(Request::Schedule(node));
+      return;
+    }
+    let mut woken = U::Q::new();
+    while let Some(node) = state.read_queue.pop() {
+      state.readers += 1;
+      woken.push(node);
+    }
+    drop(state);
+    while let Some(node) = woken.pop() {
+      Thread::<U>::suspend(Request::Schedule(node));
+    }
+  }
+
+}
+
+impl<'a, T: ?Sized, U: SchedulerUnit> Deref for RwLockReadGuard<'a, T, U> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    unsafe { &*self.__lock.__data.get() }
+  }
+
+}
+
+impl<'a, T: ?Sized, U: SchedulerUnit> Drop for RwLockReadGuard<'a, T, U> {
+
+  fn drop(&mut self) {
+    self.__lock.read_unlock();
+  }
+
+}
+
+impl<'a, T: ?Sized, U: SchedulerUnit> Deref for RwLockWriteGuard<'a, T, U> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    unsafe { &*self.__lock.__data.get() }
+  }
+
+}
+
+impl<'a, T: ?Sized, U: SchedulerUnit> DerefMut for RwLockWriteGuard<'a, T, U> {
+
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { &mut *self.__lock.__data.get() }
+  }
+
+}
+
+impl<'a, T: ?Sized, U: SchedulerUnit> Drop for RwLockWriteGuard<'a, T, U> {
+
+  fn drop(&mut self) {
+    self.__lock.write_unlock();
+  }
+
+}
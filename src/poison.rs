@@ -12,6 +12,75 @@ use core::fmt;
 use core::marker::Reflect;
 use core::sync::atomic::{AtomicBool, Ordering};
 
+pub struct Flag { failed: AtomicBool }
+
+// Note that the Ordering uses to access the `failed` field of `Flag` below is
+// always `Relaxed`, and that's because this isn't actually protecting any data,
+// it's just a flag whether we've panicked or not.
+//
+// The actual location that this matters is when a mutex is **locked** which is
+// where we have external synchronization ensuring that we see memory
+// reads/writes to this flag.
+//
+// As a result, if it matters, we should see the correct value for `failed` in
+// all cases.
+
+impl Flag {
+    pub fn new() -> Flag {
+        Flag { failed: AtomicBool::new(false) }
+    }
+
+    #[inline]
+    pub fn borrow(&self) -> LockResult<Guard> {
+        let ret = Guard { panicking: thread_panicking() };
+        if self.get() {
+            Err(PoisonError::new(ret))
+        } else {
+            Ok(ret)
+        }
+    }
+
+    #[inline]
+    pub fn done(&self, guard: &Guard) {
+        if !guard.panicking && thread_panicking() {
+            self.failed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    pub fn get(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn clear(&self) {
+        self.failed.store(false, Ordering::Relaxed);
+    }
+}
+
+pub struct Guard {
+    panicking: bool,
+}
+
+// Panics are only caught (at the thread boundary, see `fringe_wrapper`) in
+// hosted builds. Elsewhere a panic never returns, so nothing gets poisoned.
+//
+// `std::thread::panicking` is about the whole OS thread, which other barn
+// threads run on while one unwinds, so barn threads keep their own flag.
+#[cfg(feature = "hosted")]
+fn thread_panicking() -> bool {
+    if ::arch::Arch::<()>::in_thread() {
+        ::arch::Arch::<()>::panicking()
+    } else {
+        ::std::thread::panicking()
+    }
+}
+
+#[cfg(not(feature = "hosted"))]
+fn thread_panicking() -> bool {
+    false
+}
+
 /// A type of error which can be returned whenever a lock is acquired.
 ///
 /// Both Mutexes and RwLocks are poisoned whenever a thread fails while the lock
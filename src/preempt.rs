@@ -0,0 +1,165 @@
+// Time slicing. A timer interrupt handler calls `preempt_tick`, which makes
+// the running thread yield once it has used up its quantum.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use arch;
+use scheduler::{Thread, Request, SchedulerUnit};
+
+type Arch<U> = ::arch::Arch<Thread<U>>;
+
+/// Ticks a thread runs for before it is preempted, unless changed with
+/// `set_quantum`.
+pub const DEFAULT_QUANTUM: usize = 10;
+
+static QUANTUM: AtomicUsize = AtomicUsize::new(DEFAULT_QUANTUM);
+
+pub fn quantum() -> usize {
+  QUANTUM.load(Ordering::SeqCst)
+}
+
+/// Sets the quantum for every CPU, returning the old one so that it can be
+/// put back.
+pub fn set_quantum(ticks: usize) -> usize {
+  assert!(ticks > 0, "quantum must be at least one tick");
+  QUANTUM.swap(ticks, Ordering::SeqCst)
+}
+
+/// To be called from the timer interrupt handler, on the interrupted stack.
+///
+/// Once the running thread has used up its quantum it is made to yield, as
+/// if it had called `Thread::suspend(Request::Yield)` itself. Ticks that
+/// arrive while the scheduler is running are ignored, and a thread inside a
+/// `no_preempt` section or holding a spin lock is only preempted on the
+/// first tick after it leaves it.
+pub fn preempt_tick<U: SchedulerUnit>() {
+  // No debug! in here: it isn't safe to print from a signal handler.
+  if !Arch::<U>::in_thread() {
+    return;
+  }
+  if Arch::<U>::tick() < quantum() || Arch::<U>::preempt_disabled() {
+    return;
+  }
+  Thread::<U>::suspend(Request::Yield);
+}
+
+/// Keeps the running thread from being preempted until dropped.
+///
+/// A thread switched out while it holds a lock the scheduler doesn't know
+/// about leaves the next thread on the CPU to take that lock spinning
+/// forever. The heap's lock is the usual one: barn allocates and frees with
+/// preemption off, and threads that can be preempted should too, including
+/// when they drop the last handle to something on the heap. The guard must
+/// not be held across anything that can switch threads, like locking a
+/// `Mutex`.
+pub struct NoPreempt {
+  _private: (),
+}
+
+impl !Send for NoPreempt {}
+
+impl NoPreempt {
+
+  pub fn new() -> NoPreempt {
+    arch::preempt_disable();
+    NoPreempt { _private: () }
+  }
+
+}
+
+impl Drop for NoPreempt {
+
+  fn drop(&mut self) {
+    arch::preempt_enable();
+  }
+
+}
+
+/// Runs `f` without being preempted, e.g. `no_preempt(|| Box::new(x))`.
+pub fn no_preempt<T, F: FnOnce() -> T>(f: F) -> T {
+  let _guard = NoPreempt::new();
+  f()
+}
+
+#[cfg(feature = "hosted")]
+pub use self::hosted::Timer;
+
+#[cfg(feature = "hosted")]
+mod hosted {
+
+  use std::mem;
+  use std::ptr;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+  use std::thread;
+  use std::time::Duration;
+
+  use libc;
+
+  use scheduler::SchedulerUnit;
+  use super::preempt_tick;
+
+  // The `preempt_tick` instance the signal handler calls, as a `fn()`.
+  static TICK: AtomicUsize = ATOMIC_USIZE_INIT;
+
+  extern "C" fn on_alarm(_: libc::c_int) {
+    let tick = TICK.load(Ordering::SeqCst);
+    if tick != 0 {
+      let tick: fn() = unsafe { mem::transmute(tick) };
+      tick();
+    }
+  }
+
+  /// Simulates a timer interrupt for the OS thread that starts it, by
+  /// sending that thread SIGALRM every `interval`. (`setitimer` would
+  /// signal whichever thread of the process happened to be running.)
+  ///
+  /// The timer stops when dropped. The signal handler stays installed, but
+  /// ignores signals on OS threads that aren't running a barn thread.
+  ///
+  /// Threads it preempts must not be switched out inside the allocator, so
+  /// they have to allocate and free inside `no_preempt`, see `NoPreempt`.
+  pub struct Timer {
+    stop: Arc<AtomicBool>,
+    ticker: Option<thread::JoinHandle<()>>,
+  }
+
+  impl Timer {
+
+    pub fn start<U: SchedulerUnit>(interval: Duration) -> Timer {
+      TICK.store(preempt_tick::<U> as fn() as usize, Ordering::SeqCst);
+      unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = on_alarm as libc::sighandler_t;
+        // With SA_NODEFER a thread preempted inside the handler doesn't
+        // keep SIGALRM blocked while the others run.
+        action.sa_flags = libc::SA_RESTART | libc::SA_NODEFER;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGALRM, &action, ptr::null_mut());
+      }
+      let target = unsafe { libc::pthread_self() };
+      let stop = Arc::new(AtomicBool::new(false));
+      let their_stop = stop.clone();
+      let ticker = thread::spawn(move || {
+        while !their_stop.load(Ordering::SeqCst) {
+          thread::sleep(interval);
+          unsafe { libc::pthread_kill(target, libc::SIGALRM) };
+        }
+      });
+      Timer { stop: stop, ticker: Some(ticker) }
+    }
+
+  }
+
+  impl Drop for Timer {
+
+    fn drop(&mut self) {
+      self.stop.store(true, Ordering::SeqCst);
+      if let Some(ticker) = self.ticker.take() {
+        let _ = ticker.join();
+      }
+    }
+
+  }
+
+}
@@ -0,0 +1,456 @@
+// A SchedulerUnit that always runs the highest-priority runnable thread,
+// round-robin among threads of equal priority.
+
+extern crate alloc;
+
+use self::alloc::arc::Arc;
+use self::alloc::boxed::Box;
+use core::cmp::max;
+
+use fringe::OwnedStack;
+use scheduler::{self, Request};
+use lock;
+use thread;
+use channel;
+use irq;
+use stack;
+use basic;
+
+/// Number of priority levels. 0 is the lowest priority, `LEVELS - 1` the highest.
+pub const LEVELS: usize = 8;
+
+/// Priority given to threads that never set one.
+pub const DEFAULT_PRIORITY: usize = LEVELS / 2;
+
+pub struct Unit;
+impl ::scheduler::SchedulerUnit for Unit {
+  type L = Local;
+  type N = Node;
+  type Q = Queue;
+  type S = OwnedStack;
+  type C = basic::Clock;
+}
+
+pub struct Local {
+  priority: usize,
+  // Highest priority lent by waiters on inheriting mutexes we hold.
+  lent: usize,
+  links: lock::Links<Unit>,
+  affinity: usize,
+}
+
+impl Default for Local {
+
+  fn default() -> Local {
+    Local { priority: DEFAULT_PRIORITY, lent: 0, links: lock::Links::default(), affinity: !0 }
+  }
+
+}
+
+impl Local {
+
+  // Priority the thread runs at, including any lent to it.
+  pub fn priority(&self) -> usize {
+    max(self.priority, self.lent)
+  }
+
+  pub fn base_priority(&self) -> usize {
+    self.priority
+  }
+
+  // Only takes effect the next time the thread is queued, use
+  // `set_priority` for the running thread.
+  pub fn set_priority(&mut self, priority: usize) {
+    assert!(priority < LEVELS, "priority out of range");
+    self.priority = priority;
+  }
+
+  // Restricts which CPUs a balanced scheduler may move the thread to.
+  pub fn set_affinity(&mut self, mask: usize) {
+    self.affinity = mask;
+  }
+
+}
+
+impl scheduler::Affinity for Local {
+
+  fn affinity(&self) -> usize {
+    self.affinity
+  }
+
+}
+
+impl lock::Inherit<Unit> for Local {
+
+  fn priority(&self) -> usize {
+    Local::priority(self)
+  }
+
+  fn base_priority(&self) -> usize {
+    Local::base_priority(self)
+  }
+
+  fn lend_priority(&mut self, priority: usize) {
+    self.lent = priority;
+  }
+
+  fn links(&mut self) -> &mut lock::Links<Unit> {
+    &mut self.links
+  }
+
+}
+
+pub type Node = Box<scheduler::Thread<Unit>>;
+type ThreadList = scheduler::ThreadList<Unit>;
+pub type Scheduler = scheduler::Scheduler<Unit>;
+pub type Mutex<T> = lock::Mutex<T, Unit>;
+pub type MutexGuard<'a, T> = lock::MutexGuard<'a, T, Unit>;
+pub type Condvar = lock::Condvar<Unit>;
+pub type RwLock<T> =  lock::RwLock<T, Unit>;
+pub type RwLockReadGuard<'a, T> = lock::RwLockReadGuard<'a, T, Unit>;
+pub type RwLockWriteGuard<'a, T> = lock::RwLockWriteGuard<'a, T, Unit>;
+pub type Semaphore = lock::Semaphore<Unit>;
+pub type SemaphoreGuard<'a> = lock::SemaphoreGuard<'a, Unit>;
+pub type Sender<T> = channel::Sender<T, Unit>;
+pub type SyncSender<T> = channel::SyncSender<T, Unit>;
+pub type Receiver<T> = channel::Receiver<T, Unit>;
+pub type OneshotSender<T> = channel::OneshotSender<T, Unit>;
+pub type OneshotReceiver<T> = channel::OneshotReceiver<T, Unit>;
+pub type IrqWaker = irq::IrqWaker<Unit>;
+pub type Thread = scheduler::Thread<Unit>;
+pub type JoinHandle<T> = thread::JoinHandle<T, Unit>;
+pub type StackPool = stack::StackPool<OwnedStack>;
+
+/// Spawns `f` on a new thread at `priority` from inside a running thread.
+pub fn spawn<F, T>(stack: OwnedStack, priority: usize, f: F) -> JoinHandle<T>
+  where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+  let (mut t, handle) = thread::new::<Unit, _, _>(stack, f);
+  t.local_mut().set_priority(priority);
+  Thread::suspend(Request::Schedule(<Node as scheduler::Node<Unit>>::new(t)));
+  handle
+}
+
+/// Configures a new thread: its stack, name and priority.
+pub struct Builder {
+  inner: thread::Builder<Unit>,
+  priority: usize,
+}
+
+impl Builder {
+
+  pub fn new() -> Builder {
+    Builder { inner: thread::Builder::new(), priority: DEFAULT_PRIORITY }
+  }
+
+  pub fn stack_size(self, size: usize) -> Builder {
+    Builder { inner: self.inner.stack_size(size), priority: self.priority }
+  }
+
+  pub fn name(self, name: &'static str) -> Builder {
+    Builder { inner: self.inner.name(name), priority: self.priority }
+  }
+
+  pub fn stack_pool(self, pool: Arc<StackPool>) -> Builder {
+    Builder { inner: self.inner.stack_pool(pool), priority: self.priority }
+  }
+
+  pub fn paint_stack(self, paint: bool) -> Builder {
+    Builder { inner: self.inner.paint_stack(paint), priority: self.priority }
+  }
+
+  pub fn priority(mut self, priority: usize) -> Builder {
+    assert!(priority < LEVELS, "priority out of range");
+    self.priority = priority;
+    self
+  }
+
+  pub fn build<F, T>(self, f: F) -> (Thread, JoinHandle<T>)
+    where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    let (mut t, handle) = self.inner.build(f);
+    t.local_mut().set_priority(self.priority);
+    (t, handle)
+  }
+
+  pub fn spawn<F, T>(self, f: F) -> JoinHandle<T>
+    where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    let (t, handle) = self.build(f);
+    Thread::suspend(Request::Schedule(<Node as scheduler::Node<Unit>>::new(t)));
+    handle
+  }
+
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+  channel::channel::<T, Unit>()
+}
+
+pub fn sync_channel<T>(bound: usize) -> (SyncSender<T>, Receiver<T>) {
+  channel::sync_channel::<T, Unit>(bound)
+}
+
+pub fn oneshot<T>() -> (OneshotSender<T>, OneshotReceiver<T>) {
+  channel::oneshot::<T, Unit>()
+}
+
+/// Priority of the running thread.
+pub fn priority() -> usize {
+  Thread::current().local().priority()
+}
+
+/// Changes the base priority of the running thread. The thread is requeued
+/// at its new level, so a lower priority lets other threads run straight
+/// away. Priority lent through inheriting mutexes still applies on top.
+pub fn set_priority(priority: usize) {
+  Thread::current_mut().local_mut().set_priority(priority);
+  Thread::suspend(Request::Yield);
+}
+
+impl scheduler::Node<Unit> for Node {
+
+  fn new(t: Thread) -> Self {
+    box t
+  }
+
+  fn deref(&self) -> &Thread {
+    &**self
+  }
+
+  fn deref_mut(&mut self) -> &mut Thread {
+    &mut **self
+  }
+
+  fn into_thread(self) -> Thread {
+    *self
+  }
+
+}
+
+/// A multi-level ready queue with one FIFO list per priority.
+pub struct Queue {
+  levels: [ThreadList; LEVELS],
+}
+
+impl Queue {
+
+  // Highest level with a thread in it.
+  fn top(&self) -> Option<usize> {
+    (0..LEVELS).rev().find(|&level| !self.levels[level].is_empty())
+  }
+
+}
+
+impl ::scheduler::Queue<Unit> for Queue {
+
+  fn new() -> Queue {
+    Queue { levels: [ThreadList::new(), ThreadList::new(), ThreadList::new(), ThreadList::new(),
+                     ThreadList::new(), ThreadList::new(), ThreadList::new(), ThreadList::new()] }
+  }
+
+  fn push(&mut self, node: Node) {
+    let level = node.local().priority();
+    self.levels[level].push_back(node);
+  }
+
+  fn pop(&mut self) -> Option<Node> {
+    match self.top() {
+      Some(level) => self.levels[level].pop_front(),
+      None => None,
+    }
+  }
+
+  fn front(&self) -> Option<&Node> {
+    match self.top() {
+      Some(level) => self.levels[level].front(),
+      None => None,
+    }
+  }
+
+  fn front_mut(&mut self) -> Option<&mut Node> {
+    match self.top() {
+      Some(level) => self.levels[level].front_mut(),
+      None => None,
+    }
+  }
+
+  // The thread's priority may have changed since it was queued, so its
+  // level has to be looked for.
+  fn remove(&mut self, thread: &Thread) -> Option<Node> {
+    match self.levels.iter_mut().find(|level| level.contains(thread)) {
+      Some(level) => level.remove(thread),
+      None => None,
+    }
+  }
+
+  // Takes the lowest-priority thread that may go.
+  fn steal(&mut self, can_take: &Fn(&Thread) -> bool) -> Option<Node> {
+    let front = match self.front() {
+      Some(node) => &**node as *const Thread,
+      None => return None,
+    };
+    for level in self.levels.iter_mut() {
+      if let Some(node) = level.remove_where(&|t| t as *const Thread != front && can_take(t)) {
+        return Some(node);
+      }
+    }
+    None
+  }
+
+  fn for_each(&self, f: &mut FnMut(&Thread)) {
+    for level in self.levels.iter().rev() {
+      level.for_each(f);
+    }
+  }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use super::*;
+  use scheduler::Queue as QueueTrait;
+  use fringe::OwnedStack;
+
+  fn thread<F: FnOnce() + Send + 'static>(priority: usize, f: F) -> Thread {
+    let stack = OwnedStack::new(1024 * 1024);
+    let mut t = Thread::new(stack, f);
+    t.local_mut().set_priority(priority);
+    t
+  }
+
+  #[test]
+  fn runs_highest_first() {
+    let mut q = Queue::new();
+    let order = Arc::new(::std::sync::Mutex::new(vec!()));
+    for &p in [1, 6, 3, 6].iter() {
+      let order = order.clone();
+      q.push(Box::new(thread(p, move || {
+        order.lock().unwrap().push(p);
+      })));
+    }
+    Scheduler::new(q).run();
+    assert_eq!(*order.lock().unwrap(), vec!(6, 6, 3, 1));
+  }
+
+  #[test]
+  fn woken_thread_preempts() {
+    let mut q = Queue::new();
+    let order = Arc::new(::std::sync::Mutex::new(vec!()));
+    let o = order.clone();
+    q.push(Box::new(thread(2, move || {
+      let o2 = o.clone();
+      let h = spawn(OwnedStack::new(1024 * 1024), 5, move || o2.lock().unwrap().push("high"));
+      o.lock().unwrap().push("low");
+      h.join();
+    })));
+    Scheduler::new(q).run();
+    assert_eq!(*order.lock().unwrap(), vec!("high", "low"));
+  }
+
+  #[test]
+  fn set_priority_requeues() {
+    let mut q = Queue::new();
+    let order = Arc::new(::std::sync::Mutex::new(vec!()));
+    let (o1, o2) = (order.clone(), order.clone());
+    q.push(Box::new(thread(4, move || {
+      set_priority(1);
+      assert_eq!(priority(), 1);
+      o1.lock().unwrap().push(1);
+    })));
+    q.push(Box::new(thread(3, move || {
+      o2.lock().unwrap().push(3);
+    })));
+    Scheduler::new(q).run();
+    assert_eq!(*order.lock().unwrap(), vec!(3, 1));
+  }
+
+  #[test]
+  fn inheritance_avoids_inversion() {
+    let mut q = Queue::new();
+    let order = Arc::new(::std::sync::Mutex::new(vec!()));
+    let lock = Arc::new(Mutex::new_inheriting(()));
+    let (o, l) = (order.clone(), lock.clone());
+
+    q.push(Box::new(thread(1, move || {
+      let g = l.lock().unwrap();
+      let (o2, l2) = (o.clone(), l.clone());
+      // The medium thread preempts us and starts a high one that blocks on
+      // our lock. We must now run ahead of the medium thread.
+      spawn(OwnedStack::new(1024 * 1024), 4, move || {
+        let (o3, l3) = (o2.clone(), l2.clone());
+        spawn(OwnedStack::new(1024 * 1024), 6, move || {
+          let _g = l3.lock().unwrap();
+          o3.lock().unwrap().push("high");
+        });
+        o2.lock().unwrap().push("medium");
+      });
+      assert_eq!(priority(), 6);
+      o.lock().unwrap().push("low");
+      drop(g);
+      assert_eq!(priority(), 1);
+    })));
+    Scheduler::new(q).run();
+    assert_eq!(*order.lock().unwrap(), vec!("low", "high", "medium"));
+  }
+
+  #[test]
+  fn inheritance_is_transitive() {
+    let mut q = Queue::new();
+    let order = Arc::new(::std::sync::Mutex::new(vec!()));
+    let a = Arc::new(Mutex::new_inheriting(()));
+    let b = Arc::new(Mutex::new_inheriting(()));
+    let (o, a1, b1) = (order.clone(), a.clone(), b.clone());
+
+    q.push(Box::new(thread(1, move || {
+      let g = a1.lock().unwrap();
+      // Takes `b` then blocks on `a`.
+      let (o2, a2, b2) = (o.clone(), a1.clone(), b1.clone());
+      spawn(OwnedStack::new(1024 * 1024), 2, move || {
+        let gb = b2.lock().unwrap();
+        let ga = a2.lock().unwrap();
+        o2.lock().unwrap().push("mid");
+        drop(ga);
+        drop(gb);
+      });
+      // Blocks on `b`, which should boost both holders.
+      let (o3, b3) = (o.clone(), b1.clone());
+      spawn(OwnedStack::new(1024 * 1024), 4, move || {
+        let o4 = o3.clone();
+        spawn(OwnedStack::new(1024 * 1024), 6, move || {
+          let _g = b3.lock().unwrap();
+          o4.lock().unwrap().push("high");
+        });
+        o3.lock().unwrap().push("medium");
+      });
+      o.lock().unwrap().push("low");
+      drop(g);
+    })));
+    Scheduler::new(q).run();
+    assert_eq!(*order.lock().unwrap(), vec!("low", "mid", "high", "medium"));
+  }
+
+  #[test]
+  fn inheritance_ends_when_waiter_times_out() {
+    use time::ManualClock;
+
+    let mut q = Queue::new();
+    let lock = Arc::new(Mutex::new_inheriting(()));
+    let l = lock.clone();
+    ManualClock::set(0);
+
+    q.push(Box::new(thread(1, move || {
+      let g = l.lock().unwrap();
+      let l2 = l.clone();
+      let h = spawn(OwnedStack::new(1024 * 1024), 6, move || l2.try_lock_for(5).is_ok());
+      assert_eq!(priority(), 6);
+      // The high thread gives up while we sleep, and takes its priority back.
+      Thread::sleep(10);
+      assert_eq!(priority(), 1);
+      drop(g);
+      assert!(!h.join());
+    })));
+    Scheduler::new(q).run();
+  }
+
+}
@@ -0,0 +1,227 @@
+// Rate-monotonic scheduling on top of the priority unit: periodic threads
+// get fixed priorities by period, shortest first, and the task set is
+// checked with response-time analysis before any of them runs.
+//
+// The analysis works in whatever unit `Params` is given in (ticks, say). It
+// assumes independent threads released together, the worst case, and counts
+// threads that end up sharing a level as interfering with each other.
+
+use basic;
+use priority::{self, Builder, Thread, LEVELS};
+use scheduler::Duration;
+use time::Clock;
+
+pub use edf::Params;
+
+/// A periodic thread in the task set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Task {
+  pub params: Params,
+  /// Set by `assign_priorities`.
+  pub priority: usize,
+  /// Worst-case response time, set by `response_times` if it was found to
+  /// be within the deadline.
+  pub response: Option<usize>,
+}
+
+impl Task {
+
+  pub fn new(params: Params) -> Task {
+    Task { params: params, priority: 0, response: None }
+  }
+
+  /// A priority `Builder` for the task's thread.
+  pub fn builder(&self) -> Builder {
+    Builder::new().priority(self.priority)
+  }
+
+}
+
+/// Gives each task a level from `LEVELS - 1` down to `lowest`, shorter
+/// periods higher. Tasks with the same period share a level, as do all the
+/// tasks that don't get one of their own once the levels run out.
+pub fn assign_priorities(tasks: &mut [Task], lowest: usize) {
+  assert!(lowest < LEVELS, "priority out of range");
+  for i in 0..tasks.len() {
+    // Distinct periods shorter than this one.
+    let shorter = (0..tasks.len())
+      .filter(|&j| tasks[j].params.period < tasks[i].params.period)
+      .filter(|&j| (0..j).all(|k| tasks[k].params.period != tasks[j].params.period))
+      .count();
+    tasks[i].priority = if shorter > LEVELS - 1 - lowest { lowest } else { LEVELS - 1 - shorter };
+  }
+}
+
+/// A task that may miss its deadline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Unschedulable {
+  /// Index of the task.
+  pub task: usize,
+}
+
+/// Works out each task's worst-case response time, from its own execution
+/// time plus that of every task at its level or above released meanwhile.
+/// Every task is analysed; those whose response time may exceed their
+/// deadline are left with `response` unset, and the first of them is
+/// returned.
+pub fn response_times(tasks: &mut [Task]) -> Result<(), Unschedulable> {
+  for task in tasks.iter_mut() {
+    assert!(task.params.period > 0, "period must be positive");
+    task.response = None;
+  }
+  let mut first = None;
+  for i in 0..tasks.len() {
+    match response_time(tasks, i) {
+      Some(response) => tasks[i].response = Some(response),
+      None => if first.is_none() {
+        first = Some(Unschedulable { task: i });
+      },
+    }
+  }
+  match first {
+    Some(e) => Err(e),
+    None => Ok(()),
+  }
+}
+
+// The worst-case response time of task `i`, unless it may pass the deadline.
+fn response_time(tasks: &[Task], i: usize) -> Option<usize> {
+  let deadline = tasks[i].params.deadline;
+  let mut response = tasks[i].params.wcet;
+  loop {
+    let mut next = tasks[i].params.wcet;
+    for j in 0..tasks.len() {
+      if j != i && tasks[j].priority >= tasks[i].priority {
+        let period = tasks[j].params.period;
+        next += (response + period - 1) / period * tasks[j].params.wcet;
+      }
+    }
+    if next > deadline {
+      return None;
+    }
+    if next == response {
+      return Some(response);
+    }
+    response = next;
+  }
+}
+
+/// What `plan` does with a task set that fails the analysis.
+#[derive(Clone, Copy)]
+pub enum Policy {
+  Refuse,
+  /// Call the function once for each task that may miss its deadline,
+  /// then go ahead anyway.
+  Warn(fn(Unschedulable)),
+}
+
+/// Assigns priorities and runs the analysis, to be called before the
+/// scheduler starts.
+pub fn plan(tasks: &mut [Task], lowest: usize, policy: Policy) -> Result<(), Unschedulable> {
+  assign_priorities(tasks, lowest);
+  match (response_times(tasks), policy) {
+    (Err(_), Policy::Warn(f)) => {
+      for (i, task) in tasks.iter().enumerate() {
+        if task.response.is_none() {
+          f(Unschedulable { task: i });
+        }
+      }
+      Ok(())
+    }
+    (result, _) => result,
+  }
+}
+
+/// Calls `f` once a period, starting now, until it returns false.
+pub fn periodic<F: FnMut() -> bool>(period: Duration<priority::Unit>, mut f: F) {
+  let mut release = basic::Clock::now();
+  while f() {
+    release = basic::Clock::after(release, period);
+    Thread::sleep_until(release);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::boxed::Box;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+  use super::*;
+  use basic;
+  use priority::{Queue, Scheduler};
+  use scheduler::Queue as QueueTrait;
+
+  fn tasks(set: &[(usize, usize)]) -> ::std::vec::Vec<Task> {
+    set.iter().map(|&(period, wcet)| Task::new(Params::new(period, wcet))).collect()
+  }
+
+  #[test]
+  fn shorter_periods_get_higher_priorities() {
+    let mut set = tasks(&[(50, 1), (10, 1), (20, 1), (10, 1), (100, 1)]);
+    assign_priorities(&mut set, 5);
+    let levels: ::std::vec::Vec<_> = set.iter().map(|t| t.priority).collect();
+    assert_eq!(levels, vec!(5, 7, 6, 7, 5));
+  }
+
+  #[test]
+  fn response_time_analysis() {
+    let mut set = tasks(&[(7, 3), (12, 3), (20, 5)]);
+    assert_eq!(plan(&mut set, 0, Policy::Refuse), Ok(()));
+    let responses: ::std::vec::Vec<_> = set.iter().map(|t| t.response).collect();
+    assert_eq!(responses, vec!(Some(3), Some(6), Some(20)));
+
+    // Each failing task is warned about, as a bit.
+    static WARNED: AtomicUsize = ATOMIC_USIZE_INIT;
+    fn warn(e: Unschedulable) {
+      WARNED.fetch_or(1 << e.task, Ordering::SeqCst);
+    }
+    let mut set = tasks(&[(7, 3), (12, 3), (20, 6)]);
+    assert_eq!(plan(&mut set, 0, Policy::Refuse), Err(Unschedulable { task: 2 }));
+    assert_eq!(plan(&mut set, 0, Policy::Warn(warn)), Ok(()));
+    assert_eq!(WARNED.load(Ordering::SeqCst), 0b100);
+    assert_eq!(set[2].response, None);
+
+    // Both the second and third miss, and earlier results don't linger.
+    let mut set = tasks(&[(7, 3), (12, 7), (20, 6)]);
+    set[1].response = Some(1);
+    WARNED.store(0, Ordering::SeqCst);
+    assert_eq!(plan(&mut set, 0, Policy::Warn(warn)), Ok(()));
+    assert_eq!(WARNED.load(Ordering::SeqCst), 0b110);
+    let responses: ::std::vec::Vec<_> = set.iter().map(|t| t.response).collect();
+    assert_eq!(responses, vec!(Some(3), None, None));
+  }
+
+  #[test]
+  #[should_panic(expected = "period must be positive")]
+  fn zero_period_rejected() {
+    let mut set = tasks(&[(10, 1), (0, 1)]);
+    let _ = response_times(&mut set);
+  }
+
+  #[test]
+  fn periodic_threads_run_by_rate() {
+    basic::Clock::set(0);
+    let mut set = tasks(&[(20, 2), (10, 2)]);
+    plan(&mut set, 1, Policy::Refuse).unwrap();
+    let runs = Arc::new(::std::sync::Mutex::new(vec!()));
+    let mut q = Queue::new();
+    for task in set.iter() {
+      let (runs, period) = (runs.clone(), task.params.period);
+      let (t, _) = task.builder().build(move || {
+        let mut left = 40 / period;
+        periodic(period, || {
+          runs.lock().unwrap().push((basic::Clock::now(), period));
+          left -= 1;
+          left > 0
+        });
+      });
+      q.push(Box::new(t));
+    }
+
+    Scheduler::new(q).run();
+    assert_eq!(*runs.lock().unwrap(),
+               vec!((0, 10), (0, 20), (10, 10), (20, 10), (20, 20), (30, 10)));
+  }
+
+}
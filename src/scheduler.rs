@@ -1,16 +1,37 @@
 #![allow(dead_code)]
 
+use core::fmt;
 use core::mem::{transmute};
+use core::marker::PhantomData;
+use core::ptr;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+#[cfg(feature = "alloc")]
+use self::alloc::arc::Arc;
 
 use fringe_wrapper::Group;
+use fringe::Stack;
+use irq::IrqWaker;
+use stack::{self, StackUsage, OverflowPolicy};
+#[cfg(feature = "alloc")]
+use stack::StackPool;
+use time::Clock;
 
 pub trait SchedulerUnit where Self: Sized + 'static {
   type L: Default;
   type Q: Queue<Self>;
   type N: Node<Self>;
   type S: ::fringe::Stack;
+  type C: Clock;
 }
 
+pub type Instant<U> = <<U as SchedulerUnit>::C as Clock>::Instant;
+pub type Duration<U> = <<U as SchedulerUnit>::C as Clock>::Duration;
+
 pub trait Node<U: SchedulerUnit> where Self: Send + Sized {
 
   fn new(t: Thread<U>) -> Self;
@@ -19,6 +40,8 @@ pub trait Node<U: SchedulerUnit> where Self: Send + Sized {
   fn deref(&self) -> &Thread<U>;
 
   fn deref_mut(&mut self) -> &mut Thread<U>;
+
+  fn into_thread(self) -> Thread<U>;
 }
 
 // Must do no allocations for these methods
@@ -34,12 +57,531 @@ pub trait Queue<U: SchedulerUnit> where Self: Sized + Sync + 'static {
   fn front(&self) -> Option<&U::N>;
 
   fn front_mut(&mut self) -> Option<&mut U::N>;
+
+  // Removes the node holding `thread`, if it is in this queue.
+  fn remove(&mut self, thread: &Thread<U>) -> Option<U::N>;
+
+  // Removes some node other than the front one for which `can_take` holds,
+  // so that an idle CPU can run it.
+  fn steal(&mut self, can_take: &Fn(&Thread<U>) -> bool) -> Option<U::N>;
+
+  // Calls `f` on every thread in the queue, front first.
+  fn for_each(&self, f: &mut FnMut(&Thread<U>));
+
+  // Called once the front thread is back from running, with when it was
+  // resumed, for queues that order threads by how long they ran. It must
+  // stay at the front.
+  fn ran(&mut self, _since: Instant<U>) {}
+}
+
+/// Implemented by thread locals to pin threads to some CPUs.
+pub trait Affinity {
+
+  /// Bit `i` is set if the thread may be moved to CPU `i`.
+  fn affinity(&self) -> usize;
+
 }
 
+fn may_run_on<U: SchedulerUnit>(thread: &Thread<U>, cpu: usize) -> bool where U::L: Affinity {
+  cpu < 8 * size_of::<usize>() && thread.local.affinity() & (1 << cpu) != 0
+}
+
+
+/// A unique identifier for a thread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(usize);
+
+static NEXT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+impl ThreadId {
+
+  fn next() -> ThreadId {
+    ThreadId(NEXT_ID.fetch_add(1, Ordering::SeqCst) + 1)
+  }
+
+  pub fn as_usize(&self) -> usize {
+    self.0
+  }
+
+}
+
+impl fmt::Display for ThreadId {
+
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "#{}", self.0)
+  }
+
+}
+
+/// What a parked thread is waiting for: the kind of primitive (e.g.
+/// `"mutex"`) and its address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockedOn {
+  pub kind: &'static str,
+  pub object: usize,
+}
+
+impl BlockedOn {
+
+  pub fn new<T: ?Sized>(kind: &'static str, object: &T) -> BlockedOn {
+    BlockedOn { kind: kind, object: object as *const T as *const u8 as usize }
+  }
+
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+  Running,
+  Ready,
+  Sleeping,
+  // `None` for threads parked without saying on what.
+  Blocked(Option<BlockedOn>),
+}
+
+/// A snapshot of one thread, from `Scheduler::threads` or `Thread::inspect`.
+#[derive(Clone, Copy, Debug)]
+pub struct ThreadInfo {
+  pub id: ThreadId,
+  pub name: Option<&'static str>,
+  pub state: State,
+  pub stack: StackUsage,
+}
 
 pub struct Thread<U: SchedulerUnit> {
   group: Group<'static, Response<U>, Request<U>, U::S>,
-  local: U::L
+  local: U::L,
+  id: ThreadId,
+  name: Option<&'static str>,
+  wake_at: Option<Instant<U>>,
+  timer: Option<Timer<U>>,
+  timed_out: bool,
+  // The CPU whose scheduler parked the thread, while it is parked.
+  parked_on: CpuRef<U>,
+  blocked_on: Option<BlockedOn>,
+  // Links in the parking scheduler's list of parked threads.
+  parked_links: ParkedLinks<U>,
+  // Where the stack goes once the thread finishes.
+  #[cfg(feature = "alloc")]
+  pool: Option<Arc<StackPool<U::S>>>,
+  stack_base: usize,
+  stack_limit: usize,
+  canary: usize,
+  // Whether the stack was painted, so `stack_usage` can scan it.
+  painted: bool,
+  // Killed for overflowing its stack.
+  overflowed: bool,
+  // Unwinding from a panic, which the guards of the locks it held go by.
+  panicking: bool,
+  // Links in the `ThreadList` the thread is in, if any.
+  list_links: ListLinks<U>,
+}
+
+struct ParkedLinks<U: SchedulerUnit> {
+  prev: *mut Thread<U>,
+  next: *mut Thread<U>,
+}
+
+// Like `Timer::next`, only followed by the scheduler that parked the thread.
+unsafe impl<U: SchedulerUnit> Send for ParkedLinks<U> {}
+
+impl<U: SchedulerUnit> ParkedLinks<U> {
+
+  fn none() -> ParkedLinks<U> {
+    ParkedLinks { prev: ptr::null_mut(), next: ptr::null_mut() }
+  }
+
+}
+
+struct ListLinks<U: SchedulerUnit> {
+  // The id of the list, or 0.
+  list: u64,
+  next: Option<U::N>,
+  prev: *mut Thread<U>,
+}
+
+// Only followed by whoever has the list.
+unsafe impl<U: SchedulerUnit> Send for ListLinks<U> {}
+
+impl<U: SchedulerUnit> ListLinks<U> {
+
+  fn none() -> ListLinks<U> {
+    ListLinks { list: 0, next: None, prev: ptr::null_mut() }
+  }
+
+}
+
+// 64 bits wide, so that ids never wrap around to one a live list still has.
+static NEXT_LIST: ::spin_lock::SpinLock<u64> = ::spin_lock::SpinLock::new(0);
+
+// A fresh id for a list linked through its threads, which record the id of
+// the list they are in. Never 0.
+pub(crate) fn next_list_id() -> u64 {
+  let mut next = NEXT_LIST.lock();
+  *next += 1;
+  *next
+}
+
+/// A FIFO of threads linked through the threads themselves, so that any of
+/// them can be taken out in constant time, e.g. when its wait times out.
+///
+/// Each thread owns the node of the one after it, and the list the first.
+/// A thread can only be in one list at a time.
+pub struct ThreadList<U: SchedulerUnit> {
+  // Threads record which list they are in by its id, which moves with it.
+  id: u64,
+  head: Option<U::N>,
+  tail: *mut Thread<U>,
+  len: usize,
+}
+
+unsafe impl<U: SchedulerUnit> Send for ThreadList<U> {}
+unsafe impl<U: SchedulerUnit> Sync for ThreadList<U> {}
+
+impl<U: SchedulerUnit> ThreadList<U> {
+
+  pub fn new() -> ThreadList<U> {
+    ThreadList {
+      id: next_list_id(),
+      head: None,
+      tail: ptr::null_mut(),
+      len: 0,
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  pub fn contains(&self, thread: &Thread<U>) -> bool {
+    thread.list_links.list == self.id
+  }
+
+  pub fn push_back<N: Into<U::N>>(&mut self, node: N) {
+    let mut node = node.into();
+    let thread: *mut Thread<U> = node.deref_mut();
+    unsafe {
+      debug_assert!((*thread).list_links.list == 0, "thread is already in a list");
+      (*thread).list_links.list = self.id;
+      (*thread).list_links.prev = self.tail;
+      if self.tail.is_null() {
+        self.head = Some(node);
+      } else {
+        (*self.tail).list_links.next = Some(node);
+      }
+    }
+    self.tail = thread;
+    self.len += 1;
+  }
+
+  pub fn push_front<N: Into<U::N>>(&mut self, node: N) {
+    let mut node = node.into();
+    let thread: *mut Thread<U> = node.deref_mut();
+    unsafe {
+      debug_assert!((*thread).list_links.list == 0, "thread is already in a list");
+      (*thread).list_links.list = self.id;
+      match self.head.take() {
+        Some(mut old) => {
+          old.deref_mut().list_links.prev = thread;
+          (*thread).list_links.next = Some(old);
+        }
+        None => self.tail = thread,
+      }
+    }
+    self.head = Some(node);
+    self.len += 1;
+  }
+
+  pub fn pop_front(&mut self) -> Option<U::N> {
+    let front = self.head.as_mut().map(|node| node.deref_mut() as *mut Thread<U>);
+    front.map(|thread| unsafe { self.unlink(thread) })
+  }
+
+  pub fn front(&self) -> Option<&U::N> {
+    self.head.as_ref()
+  }
+
+  pub fn front_mut(&mut self) -> Option<&mut U::N> {
+    self.head.as_mut()
+  }
+
+  /// Takes `thread` out of the list, if it is in it.
+  pub fn remove(&mut self, thread: &Thread<U>) -> Option<U::N> {
+    if !self.contains(thread) {
+      return None;
+    }
+    Some(unsafe { self.unlink(thread as *const Thread<U> as *mut Thread<U>) })
+  }
+
+  /// Takes out the first thread for which `f` holds.
+  pub fn remove_where(&mut self, f: &Fn(&Thread<U>) -> bool) -> Option<U::N> {
+    let mut thread = self.head.as_ref().map_or(ptr::null(), |node| node.deref() as *const Thread<U>);
+    while !thread.is_null() {
+      unsafe {
+        if f(&*thread) {
+          return Some(self.unlink(thread as *mut Thread<U>));
+        }
+        thread = (*thread).list_links.next.as_ref()
+                          .map_or(ptr::null(), |node| node.deref() as *const Thread<U>);
+      }
+    }
+    None
+  }
+
+  /// Takes out the last thread for which `f` holds.
+  pub fn remove_last_where(&mut self, f: &Fn(&Thread<U>) -> bool) -> Option<U::N> {
+    let mut thread = self.tail;
+    while !thread.is_null() {
+      unsafe {
+        if f(&*thread) {
+          return Some(self.unlink(thread));
+        }
+        thread = (*thread).list_links.prev;
+      }
+    }
+    None
+  }
+
+  /// Puts `node` in front of the first thread for which `f` holds, or at
+  /// the back, e.g. to keep the list sorted.
+  pub fn insert_before_first<N: Into<U::N>>(&mut self, node: N, f: &Fn(&Thread<U>) -> bool) {
+    let mut at = self.head.as_mut().map_or(ptr::null_mut(), |node| node.deref_mut() as *mut Thread<U>);
+    unsafe {
+      while !at.is_null() && !f(&*at) {
+        at = (*at).list_links.next.as_mut()
+                  .map_or(ptr::null_mut(), |node| node.deref_mut() as *mut Thread<U>);
+      }
+      if at.is_null() {
+        return self.push_back(node);
+      }
+      let prev = (*at).list_links.prev;
+      if prev.is_null() {
+        return self.push_front(node);
+      }
+      let mut node: U::N = node.into();
+      let thread: *mut Thread<U> = node.deref_mut();
+      debug_assert!((*thread).list_links.list == 0, "thread is already in a list");
+      (*thread).list_links.list = self.id;
+      (*thread).list_links.prev = prev;
+      (*thread).list_links.next = (*prev).list_links.next.take();
+      (*at).list_links.prev = thread;
+      (*prev).list_links.next = Some(node);
+    }
+    self.len += 1;
+  }
+
+  pub fn for_each(&self, f: &mut FnMut(&Thread<U>)) {
+    let mut node = self.head.as_ref();
+    while let Some(n) = node {
+      f(n.deref());
+      node = n.deref().list_links.next.as_ref();
+    }
+  }
+
+  // Unlinks `thread`, which must be in the list, and hands back its node.
+  unsafe fn unlink(&mut self, thread: *mut Thread<U>) -> U::N {
+    let prev = (*thread).list_links.prev;
+    let mut next = (*thread).list_links.next.take();
+    (*thread).list_links = ListLinks::none();
+    match next {
+      Some(ref mut next) => next.deref_mut().list_links.prev = prev,
+      None => self.tail = prev,
+    }
+    self.len -= 1;
+    let link = if prev.is_null() { &mut self.head } else { &mut (*prev).list_links.next };
+    ::core::mem::replace(link, next).unwrap()
+  }
+
+}
+
+impl<U: SchedulerUnit> Drop for ThreadList<U> {
+
+  // One node at a time, rather than each dropping the rest.
+  fn drop(&mut self) {
+    while let Some(node) = self.pop_front() {
+      drop(node);
+    }
+  }
+
+}
+
+impl<U: SchedulerUnit> Queue<U> for ThreadList<U> {
+
+  fn new() -> ThreadList<U> {
+    ThreadList::new()
+  }
+
+  fn push(&mut self, node: U::N) {
+    self.push_back(node);
+  }
+
+  fn pop(&mut self) -> Option<U::N> {
+    self.pop_front()
+  }
+
+  fn front(&self) -> Option<&U::N> {
+    ThreadList::front(self)
+  }
+
+  fn front_mut(&mut self) -> Option<&mut U::N> {
+    ThreadList::front_mut(self)
+  }
+
+  fn remove(&mut self, thread: &Thread<U>) -> Option<U::N> {
+    ThreadList::remove(self, thread)
+  }
+
+  fn steal(&mut self, can_take: &Fn(&Thread<U>) -> bool) -> Option<U::N> {
+    let front = match self.head.as_ref() {
+      Some(node) => node.deref() as *const Thread<U>,
+      None => return None,
+    };
+    self.remove_where(&|t| t as *const Thread<U> != front && can_take(t))
+  }
+
+  fn for_each(&self, f: &mut FnMut(&Thread<U>)) {
+    ThreadList::for_each(self, f)
+  }
+
+}
+
+// A thread parked on some other queue with a deadline. Timers are linked
+// through the threads themselves, so a thread must not move while its node
+// is parked (true of any node that owns its thread through a pointer).
+struct Timer<U: SchedulerUnit> {
+  deadline: Instant<U>,
+  cancel: &'static (Fn(&Thread<U>) -> Option<U::N> + Sync),
+  next: *mut Thread<U>,
+}
+
+// Only the scheduler that parked the thread ever follows `next`.
+unsafe impl<U: SchedulerUnit> Send for Timer<U> {}
+
+struct CpuRef<U: SchedulerUnit>(*const Cpu<U>);
+
+// A CPU outlives the threads parked on it: its scheduler doesn't return while
+// it has any.
+unsafe impl<U: SchedulerUnit> Send for CpuRef<U> {}
+
+/// The part of a scheduler that other CPUs can reach.
+///
+/// Threads are woken up on the CPU that parked them, since that CPU's
+/// scheduler owns their timers. A thread running elsewhere that wakes one of
+/// them leaves it in the parking CPU's inbox.
+pub struct Cpu<U: SchedulerUnit> {
+  inbox: ::spin_lock::SpinLock<U::Q>,
+  // One more than the index of the CPU asking this one for a thread, or 0.
+  thief: AtomicUsize,
+  finished: AtomicBool,
+  stop: AtomicBool,
+  idle: ::spin_lock::SpinLock<Option<&'static Idle>>,
+  // Wakers signalled by interrupt handlers, pushed without locking.
+  deferred: AtomicPtr<IrqWaker<U>>,
+  // The OS thread running the scheduler, once it has waited for peers.
+  #[cfg(feature = "hosted")]
+  waiter: ::spin_lock::SpinLock<Option<::std::thread::Thread>>,
+}
+
+/// What a scheduler running `forever` does when it has nothing to run.
+pub trait Idle: Sync {
+
+  /// Waits for something to happen, e.g. with `hlt`. Called again and again
+  /// while there is nothing to run, including while threads are asleep, so
+  /// it should return on every timer tick or after a short while.
+  fn idle(&self);
+
+  /// Called when a thread has been handed to the CPU from outside its
+  /// scheduler, so that `idle` can return early.
+  fn wake(&self) {}
+
+}
+
+unsafe impl<U: SchedulerUnit> Send for Cpu<U> {}
+unsafe impl<U: SchedulerUnit> Sync for Cpu<U> {}
+
+impl<U: SchedulerUnit> Cpu<U> {
+
+  pub fn new() -> Cpu<U> {
+    Cpu {
+      inbox: ::spin_lock::SpinLock::new(U::Q::new()),
+      thief: AtomicUsize::new(0),
+      finished: AtomicBool::new(false),
+      stop: AtomicBool::new(false),
+      idle: ::spin_lock::SpinLock::new(None),
+      deferred: AtomicPtr::new(ptr::null_mut()),
+      #[cfg(feature = "hosted")]
+      waiter: ::spin_lock::SpinLock::new(None),
+    }
+  }
+
+  /// Hands a thread to this CPU's scheduler from outside it, e.g. from
+  /// another OS thread. The node must be a new thread or one parked on this
+  /// CPU.
+  pub fn schedule(&self, node: U::N) {
+    self.inbox.lock().push(node);
+    self.notify();
+  }
+
+  /// Makes the scheduler return from `run` at its next chance, leaving any
+  /// threads it still has in its queues.
+  pub fn shutdown(&self) {
+    self.stop.store(true, Ordering::SeqCst);
+    self.notify();
+  }
+
+  // Lock-free, so that interrupt handlers can use it. Doesn't notify the
+  // idle hook: the interrupt itself should make `Idle::idle` return.
+  pub fn defer(&self, waker: &'static IrqWaker<U>) {
+    let waker = waker as *const IrqWaker<U> as *mut IrqWaker<U>;
+    let mut head = self.deferred.load(Ordering::SeqCst);
+    loop {
+      unsafe { (*waker).set_next(head) };
+      let old = self.deferred.compare_and_swap(head, waker, Ordering::SeqCst);
+      if old == head {
+        return;
+      }
+      head = old;
+    }
+  }
+
+  fn notify(&self) {
+    let idle = *self.idle.lock();
+    if let Some(idle) = idle {
+      idle.wake();
+    }
+    self.unpark();
+  }
+
+  // Blocks until `notify` is called, or a millisecond passes so that
+  // sleepers and timers get checked. Only SMP schedulers wait like this,
+  // and those are hosted.
+  #[cfg(feature = "hosted")]
+  fn wait(&self) {
+    {
+      let mut waiter = self.waiter.lock();
+      if waiter.is_none() {
+        *waiter = Some(::std::thread::current());
+      }
+    }
+    ::std::thread::park_timeout(::std::time::Duration::from_millis(1));
+  }
+
+  #[cfg(not(feature = "hosted"))]
+  fn wait(&self) {}
+
+  #[cfg(feature = "hosted")]
+  fn unpark(&self) {
+    if let Some(ref thread) = *self.waiter.lock() {
+      thread.unpark();
+    }
+  }
+
+  #[cfg(not(feature = "hosted"))]
+  fn unpark(&self) {}
+
 }
 
 type Arch<U: SchedulerUnit> = ::arch::Arch<(Thread<U>)>;
@@ -47,17 +589,67 @@ type Arch<U: SchedulerUnit> = ::arch::Arch<(Thread<U>)>;
 impl<U: SchedulerUnit> Thread<U> {
 
   pub fn new<F>(stack: U::S, f: F) -> Thread<U> where F: FnOnce() + Send + Sized + 'static {
+    Self::create(stack, false, f)
+  }
+
+  /// Like `new`, but paints the stack first so that `stack_usage` can tell
+  /// how much of it the thread used. Painting writes to every page of the
+  /// stack, so it costs time and, for stacks committed lazily, memory.
+  pub fn new_painted<F>(stack: U::S, f: F) -> Thread<U> where F: FnOnce() + Send + Sized + 'static {
+    Self::create(stack, true, f)
+  }
+
+  fn create<F>(stack: U::S, paint: bool, f: F) -> Thread<U> where F: FnOnce() + Send + Sized + 'static {
+    let (stack_base, stack_limit) = (stack.base(), stack.limit());
+    let canary = stack::write_canary(stack_limit);
+    // Before the group is created, which already runs on the stack.
+    if paint {
+      stack::paint(canary, stack_base);
+    }
+    // Creating the group briefly runs on the new stack.
+    let _guard = unsafe { Arch::<U>::no_preempt() };
     Thread {
-      group: Group::new(stack, f),
+      group: Group::new(stack, move || {
+        unsafe { Arch::<U>::thread_start() };
+        let _finishing = Finishing::<U>(PhantomData);
+        f()
+      }),
       local: U::L::default(),
+      id: ThreadId::next(),
+      name: None,
+      wake_at: None,
+      timer: None,
+      timed_out: false,
+      parked_on: CpuRef(ptr::null()),
+      blocked_on: None,
+      parked_links: ParkedLinks::none(),
+      #[cfg(feature = "alloc")]
+      pool: None,
+      stack_base: stack_base as usize,
+      stack_limit: stack_limit as usize,
+      canary: canary as usize,
+      painted: paint,
+      overflowed: false,
+      panicking: false,
+      list_links: ListLinks::none(),
     }
   }
 
+  pub fn with_name(mut self, name: &'static str) -> Thread<U> {
+    self.name = Some(name);
+    self
+  }
+
   pub fn suspend(request: Request<U>) -> Response<U> {
     let _guard = unsafe { Arch::<U>::no_preempt() };// no interrupts while switching
     let me = Self::current();
-    debug!("suspend current: 0x{:x}", me as *const Self as usize);
-    unsafe { me.group.suspend(request) }
+    debug!("suspend current: {}", me.id);
+    unsafe {
+      Arch::<U>::set_in_thread(false);
+      let response = me.group.suspend(request);
+      Arch::<U>::set_in_thread(true);
+      response
+    }
   }
 
   fn resume(&mut self, response: Response<U>) -> Option<Request<U>> {
@@ -66,10 +658,81 @@ impl<U: SchedulerUnit> Thread<U> {
     // thread start.
     unsafe {
       let me: &'static Self = transmute(self as *const Self);
-      debug!("resuming: setting local to : 0x{:x}", me as *const Self as usize);
+      debug!("resuming {}", me.id);
       Arch::<U>::set(me);
-      self.group.resume(response)
+      Arch::<U>::reset_ticks();
+      Arch::<U>::set_panicking(self.panicking);
+      let request = self.group.resume(response);
+      self.panicking = Arch::<U>::panicking();
+      Arch::<U>::set_panicking(false);
+      if !stack::canary_intact(self.canary as *const usize) {
+        // The request still goes through, since it may hand over a thread
+        // or release a lock, and the scheduler kills the thread after.
+        self.overflow();
+      }
+      request
+    }
+  }
+
+  // Deals with the thread having overwritten its stack canary, as the
+  // overflow policy says.
+  fn overflow(&mut self) {
+    match stack::overflow_policy() {
+      OverflowPolicy::Panic => panic!("thread {} overflowed its stack", self.id),
+      OverflowPolicy::Kill => {}
+      OverflowPolicy::Callback(f) => f(self.id, self.name),
     }
+    debug!("killing thread {}", self.id);
+    self.overflowed = true;
+  }
+
+  /// The lowest address the thread's stack may use.
+  pub fn stack_limit(&self) -> usize {
+    self.stack_limit
+  }
+
+  /// The stack's size and, if it was painted, its high-water mark. Scans
+  /// a painted stack, so takes time in proportion to how much of it is
+  /// still unused.
+  pub fn stack_usage(&self) -> StackUsage {
+    let used = if self.painted {
+      Some(stack::high_water(self.canary as *const usize, self.stack_base as *const u8))
+    } else {
+      None
+    };
+    StackUsage { size: self.stack_base - self.stack_limit, used: used }
+  }
+
+  // Puts the current thread to sleep for at least `duration`.
+  pub fn sleep(duration: Duration<U>) {
+    Self::sleep_until(U::C::after(U::C::now(), duration))
+  }
+
+  // Puts the current thread to sleep until `deadline` has passed.
+  pub fn sleep_until(deadline: Instant<U>) {
+    Self::suspend(Request::Sleep(deadline));
+  }
+
+  // Parks the current thread on `on`, handing its node to `use_node` as with
+  // `Request::make_schedule`.
+  pub fn park(on: BlockedOn, use_node: &(FnOnce(U::N) -> ())) {
+    Self::current_mut().blocked_on = Some(on);
+    Self::suspend(Request::make_schedule(use_node));
+  }
+
+  // Like `park`, except that if nobody reschedules the thread before
+  // `deadline` the scheduler takes its node back through `cancel` and runs
+  // it anyway. Returns whether the deadline passed.
+  pub fn park_until(on: BlockedOn,
+                    deadline: Instant<U>,
+                    use_node: &(FnOnce(U::N) -> ()),
+                    cancel: &(Fn(&Thread<U>) -> Option<U::N>)) -> bool {
+    Self::current_mut().blocked_on = Some(on);
+    Self::suspend(Request::make_timed_schedule(deadline, use_node, cancel));
+    let me = Self::current_mut();
+    let timed_out = me.timed_out;
+    me.timed_out = false;
+    timed_out
   }
 
   pub fn current() -> &'static Thread<U> {
@@ -80,6 +743,56 @@ impl<U: SchedulerUnit> Thread<U> {
     unsafe { Arch::<U>::get() }
   }
 
+  // Calls `f` on every thread the current thread's scheduler knows about.
+  // `f` runs in the scheduler and must not suspend.
+  pub fn inspect(f: &mut (FnMut(ThreadInfo) + Send)) {
+    let f = unsafe { transmute(f) };
+    Self::suspend(Request::Inspect(f));
+  }
+
+  pub fn id(&self) -> ThreadId {
+    self.id
+  }
+
+  pub fn name(&self) -> Option<&'static str> {
+    self.name
+  }
+
+  pub fn set_name(&mut self, name: &'static str) {
+    self.name = Some(name);
+  }
+
+  // Makes the thread give its stack to `pool` when it finishes.
+  #[cfg(feature = "alloc")]
+  pub fn set_stack_pool(&mut self, pool: Arc<StackPool<U::S>>) {
+    self.pool = Some(pool);
+  }
+
+  // Called by the scheduler once the thread's function has returned, or it
+  // was killed.
+  fn finished(self) {
+    debug!("thread {} finished", self.id);
+    if self.overflowed {
+      ::core::mem::forget(self.group);
+    } else {
+      self.recycle();
+    }
+  }
+
+  #[cfg(feature = "alloc")]
+  fn recycle(self) {
+    if let Some(pool) = self.pool {
+      pool.put(self.group.into_stack());
+    }
+  }
+
+  #[cfg(not(feature = "alloc"))]
+  fn recycle(self) {}
+
+  fn info(&self, state: State) -> ThreadInfo {
+    ThreadInfo { id: self.id, name: self.name, state: state, stack: self.stack_usage() }
+  }
+
   pub fn local(&self) -> &U::L {
     &self.local
   }
@@ -88,16 +801,50 @@ impl<U: SchedulerUnit> Thread<U> {
     &mut self.local
   }
 
+  // The CPU whose scheduler parked the thread, while it is parked.
+  pub fn parked_on(&self) -> Option<&'static Cpu<U>> {
+    unsafe { self.parked_on.0.as_ref() }
+  }
+
 }
 
 unsafe impl<U: SchedulerUnit> Send for Group<'static, Response<U>, Request<U>, U::S> {}
 
+// Dropped as a thread finishes, even by unwinding: from here on the thread is
+// on its way back to the scheduler and must not be preempted.
+struct Finishing<U: SchedulerUnit>(PhantomData<U>);
+
+impl<U: SchedulerUnit> Drop for Finishing<U> {
+
+  fn drop(&mut self) {
+    unsafe { Arch::<U>::set_in_thread(false) };
+  }
+
+}
+
 pub enum Request<U: SchedulerUnit> {
     Yield,
     Schedule(U::N),
     Unschedule(Option<&'static (Fn(U::N) -> () + Sync)>),
+    Sleep(Instant<U>),
+    UnscheduleUntil(Instant<U>,
+                    &'static (Fn(U::N) -> () + Sync),
+                    &'static (Fn(&Thread<U>) -> Option<U::N> + Sync)),
+    // Moves a thread whose local data changed (e.g. its priority) to where
+    // it now belongs in the run queue, if it is in there.
+    Requeue(ThreadRef<U>),
+    // Stops the scheduler: `run` returns without resuming any thread again.
+    Shutdown,
+    Inspect(&'static mut (FnMut(ThreadInfo) + Send)),
 }
 
+/// A thread named in a request, made with `Request::make_requeue`.
+pub struct ThreadRef<U: SchedulerUnit>(*mut Thread<U>);
+
+// Only followed by the scheduler running the thread that made the request,
+// and only once it has found the thread in its own queue.
+unsafe impl<U: SchedulerUnit> Send for ThreadRef<U> {}
+
 impl<U: SchedulerUnit> Request<U> {
 
   pub fn make_schedule(use_node: &(FnOnce(U::N) -> ())) -> Request<U> {
@@ -106,6 +853,20 @@ impl<U: SchedulerUnit> Request<U> {
     Request::Unschedule(Some(f))
   }
 
+  pub fn make_requeue(thread: *mut Thread<U>) -> Request<U> {
+    Request::Requeue(ThreadRef(thread))
+  }
+
+  pub fn make_timed_schedule(deadline: Instant<U>,
+                             use_node: &(FnOnce(U::N) -> ()),
+                             cancel: &(Fn(&Thread<U>) -> Option<U::N>)) -> Request<U> {
+    // safe because the thread stays parked, keeping both alive, until the
+    // scheduler has either used `cancel` or disarmed the timer
+    let f = unsafe { ::core::mem::transmute(use_node) };
+    let c = unsafe { ::core::mem::transmute(cancel) };
+    Request::UnscheduleUntil(deadline, f, c)
+  }
+
 }
 
 pub enum Response<U: SchedulerUnit> {
@@ -115,35 +876,451 @@ pub enum Response<U: SchedulerUnit> {
 
 pub struct Scheduler<U: SchedulerUnit> {
     queue: U::Q,
+    // Threads waiting for their `wake_at` deadline, in no particular order.
+    sleepers: U::Q,
+    // Threads parked elsewhere with a timeout, linked through `Thread::timer`.
+    timers: *mut Thread<U>,
+    // Every CPU, including ours at `id`, or empty if the only one is `own`.
+    cpus: &'static [Cpu<U>],
+    id: usize,
+    // Our CPU when created by `new`. Threads parked on it point here, which
+    // is fine since `run` doesn't return while there are any.
+    own: Cpu<U>,
+    // Threads parked on wait queues by this scheduler, linked through
+    // `Thread::parked_links`.
+    parked: usize,
+    parked_list: *mut Thread<U>,
+    // Set by `balanced`: whether a thread may be stolen onto a given CPU.
+    steal: Option<fn(&Thread<U>, usize) -> bool>,
+    // The peer we last asked for a thread, until it answers.
+    asked: Option<usize>,
+    // Peers that had nothing for us since we last had work.
+    refused: usize,
+    // Set by `forever`.
+    idle: Option<&'static Idle>,
+}
+
+fn earliest<I: Ord>(a: Option<I>, b: Option<I>) -> Option<I> {
+  match (a, b) {
+    (Some(a), Some(b)) => Some(if a <= b { a } else { b }),
+    (a, None) => a,
+    (None, b) => b,
+  }
 }
 
 impl<U: SchedulerUnit> Scheduler<U> {
   
   // Creates a scheduler with the given thread queue
   pub fn new(queue: U::Q) -> Scheduler<U> {
-    Scheduler { queue: queue }
+    Self::on(queue, &[], 0)
+  }
+
+  // Like `new`, but runs on `cpu` rather than one of its own, e.g. so that
+  // other code can hand it threads before it is created.
+  pub fn with_cpu(queue: U::Q, cpu: &'static Cpu<U>) -> Scheduler<U> {
+    Self::on(queue, unsafe { ::core::slice::from_raw_parts(cpu, 1) }, 0)
+  }
+
+  // Creates the scheduler for CPU `id`, one of several running at once on
+  // OS threads and sharing locks between their threads. Only hosted: on
+  // bare metal, `arch` keeps the current thread and preemption state in
+  // globals, so only one CPU may run threads.
+  #[cfg(feature = "hosted")]
+  pub fn new_smp(queue: U::Q, cpus: &'static [Cpu<U>], id: usize) -> Scheduler<U> {
+    Self::on(queue, cpus, id)
+  }
+
+  fn on(queue: U::Q, cpus: &'static [Cpu<U>], id: usize) -> Scheduler<U> {
+    assert!(id < cpus.len() || cpus.is_empty() && id == 0);
+    Scheduler {
+      queue: queue,
+      sleepers: U::Q::new(),
+      timers: ptr::null_mut(),
+      cpus: cpus,
+      id: id,
+      own: Cpu::new(),
+      parked: 0,
+      parked_list: ptr::null_mut(),
+      steal: None,
+      asked: None,
+      refused: 0,
+      idle: None,
+    }
+  }
+
+  // Makes `run` call `idle` instead of returning when there is nothing to
+  // run, until it is shut down.
+  pub fn forever(mut self, idle: &'static Idle) -> Scheduler<U> {
+    *self.cpu().idle.lock() = Some(idle);
+    self.idle = Some(idle);
+    self
+  }
+
+  // Lets this scheduler take threads from its peers when it runs out,
+  // except for threads whose affinity rules out this CPU.
+  #[cfg(feature = "hosted")]
+  pub fn balanced(mut self) -> Scheduler<U> where U::L: Affinity {
+    self.steal = Some(may_run_on::<U>);
+    self
+  }
+
+  pub fn cpu(&self) -> &Cpu<U> {
+    &self.cpus()[self.id]
+  }
+
+  fn cpus(&self) -> &[Cpu<U>] {
+    if self.cpus.is_empty() {
+      unsafe { ::core::slice::from_raw_parts(&self.own, 1) }
+    } else {
+      self.cpus
+    }
+  }
+
+  // Called just before `node` goes onto a wait queue, where another CPU may
+  // pick it up.
+  fn park(&mut self, node: &mut U::N) {
+    self.parked += 1;
+    let thread: *mut Thread<U> = node.deref_mut();
+    unsafe {
+      (*thread).parked_on = CpuRef(self.cpu());
+      (*thread).parked_links = ParkedLinks { prev: ptr::null_mut(), next: self.parked_list };
+      if !self.parked_list.is_null() {
+        (*self.parked_list).parked_links.prev = thread;
+      }
+    }
+    self.parked_list = thread;
+  }
+
+  // Undoes `park`, once the thread is back in our hands.
+  fn released(&mut self, thread: &mut Thread<U>) {
+    self.parked -= 1;
+    thread.parked_on = CpuRef(ptr::null());
+    thread.blocked_on = None;
+    let ParkedLinks { prev, next } = ::core::mem::replace(&mut thread.parked_links,
+                                                          ParkedLinks::none());
+    unsafe {
+      if prev.is_null() {
+        self.parked_list = next;
+      } else {
+        (*prev).parked_links.next = next;
+      }
+      if !next.is_null() {
+        (*next).parked_links.prev = prev;
+      }
+    }
+  }
+
+  // Puts a thread that is being woken up on the run queue of the CPU that
+  // parked it, or on ours if it wasn't parked (e.g. a new thread).
+  fn schedule(&mut self, mut node: U::N) {
+    let cpu = node.deref().parked_on.0;
+    if cpu.is_null() {
+      self.queue.push(node);
+    } else if cpu == self.cpu() as *const Cpu<U> {
+      self.unpark(&mut node);
+      self.queue.push(node);
+    } else {
+      debug!("waking thread parked on another cpu");
+      unsafe { (*cpu).schedule(node) };
+    }
+  }
+
+  fn unpark(&mut self, node: &mut U::N) {
+    self.released(node.deref_mut());
+    self.disarm_timer(node.deref_mut());
+  }
+
+  // Calls `f` on every thread in the run queue, asleep or parked by this
+  // scheduler (but not those handed to it and not yet picked up).
+  // Each thread's stack usage comes with it, for sizing stacks.
+  pub fn threads(&self, f: &mut FnMut(ThreadInfo)) {
+    self.visit(false, f)
+  }
+
+  fn visit(&self, running: bool, f: &mut FnMut(ThreadInfo)) {
+    let mut first = true;
+    self.queue.for_each(&mut |t| {
+      f(t.info(if first && running { State::Running } else { State::Ready }));
+      first = false;
+    });
+    self.sleepers.for_each(&mut |t| f(t.info(State::Sleeping)));
+    let mut thread = self.parked_list;
+    while !thread.is_null() {
+      unsafe {
+        f((*thread).info(State::Blocked((*thread).blocked_on)));
+        thread = (*thread).parked_links.next;
+      }
+    }
+  }
+
+  // Wakes the threads waiting on wakers that interrupt handlers signalled.
+  fn drain_deferred(&mut self) {
+    let mut waker = self.cpu().deferred.swap(ptr::null_mut(), Ordering::SeqCst);
+    while !waker.is_null() {
+      let (next, node) = unsafe { (*waker).take() };
+      if let Some(node) = node {
+        debug!("waking thread after interrupt");
+        self.schedule(node);
+      }
+      waker = next;
+    }
+  }
+
+  // Moves threads woken up or handed over by other CPUs onto the run queue.
+  fn drain_inbox(&mut self) {
+    let mut inbox = U::Q::new();
+    ::core::mem::swap(&mut *self.cpu().inbox.lock(), &mut inbox);
+    while let Some(mut node) = inbox.pop() {
+      if node.deref().parked_on.0.is_null() {
+        debug!("got a stolen thread");
+        self.refused = 0;
+      } else {
+        self.unpark(&mut node);
+      }
+      self.queue.push(node);
+    }
+  }
+
+  // Hands a thread to the peer asking for one, if we can spare one.
+  fn answer_thief(&mut self) {
+    let thief = self.cpu().thief.load(Ordering::SeqCst);
+    if thief == 0 {
+      return;
+    }
+    let can_run = self.steal.unwrap_or(never::<U>);
+    let can_take = move |t: &Thread<U>| can_run(t, thief - 1);
+    if let Some(node) = self.queue.steal(&can_take) {
+      debug!("giving a thread to cpu {}", thief - 1);
+      self.cpus()[thief - 1].schedule(node);
+    }
+    // Only cleared once the thread is in the thief's inbox, so it can't
+    // give up on us before then.
+    self.cpu().thief.store(0, Ordering::SeqCst);
+  }
+
+  // Asks our peers in turn for a thread to run. Returns false once all of
+  // them have turned us down.
+  fn try_steal(&mut self) -> bool {
+    let peers = self.cpus().len() - 1;
+    if self.steal.is_none() || peers == 0 {
+      return false;
+    }
+    if let Some(victim) = self.asked {
+      let waiting = {
+        let cpu = &self.cpus()[victim];
+        cpu.thief.load(Ordering::SeqCst) == self.id + 1 && !cpu.finished.load(Ordering::SeqCst)
+      };
+      if waiting {
+        return true;
+      }
+      // Answered: anything it gave us is in our inbox.
+      self.asked = None;
+      self.refused += 1;
+      return true;
+    }
+    if self.refused >= peers {
+      return false;
+    }
+    let victim = (self.id + 1 + self.refused) % self.cpus().len();
+    if self.cpus()[victim].thief.compare_and_swap(0, self.id + 1, Ordering::SeqCst) == 0 {
+      self.cpus()[victim].notify();
+      self.asked = Some(victim);
+    } else {
+      self.refused += 1;
+    }
+    true
+  }
+
+  // Moves every sleeper whose deadline has passed onto the run queue and
+  // returns the earliest deadline of those still asleep.
+  fn wake_sleepers(&mut self) -> Option<Instant<U>> {
+    if self.sleepers.front().is_none() {
+      return None;
+    }
+    let now = U::C::now();
+    let mut next = None;
+    let mut still_asleep = U::Q::new();
+    while let Some(mut node) = self.sleepers.pop() {
+      let deadline = node.deref().wake_at.unwrap();
+      if deadline <= now {
+        debug!("waking sleeper {}", node.deref().id);
+        node.deref_mut().wake_at = None;
+        self.queue.push(node);
+      } else {
+        next = earliest(next, Some(deadline));
+        still_asleep.push(node);
+      }
+    }
+    self.sleepers = still_asleep;
+    next
+  }
+
+  fn arm_timer(&mut self, thread: &mut Thread<U>, deadline: Instant<U>,
+               cancel: &'static (Fn(&Thread<U>) -> Option<U::N> + Sync)) {
+    thread.timer = Some(Timer { deadline: deadline, cancel: cancel, next: self.timers });
+    self.timers = thread as *mut Thread<U>;
+  }
+
+  // Called whenever a thread is rescheduled, so that a timer can't fire on a
+  // thread that was already woken up.
+  fn disarm_timer(&mut self, thread: &mut Thread<U>) {
+    if thread.timer.is_none() {
+      return;
+    }
+    let target = thread as *mut Thread<U>;
+    unsafe {
+      let mut link: *mut *mut Thread<U> = &mut self.timers;
+      while !(*link).is_null() {
+        if *link == target {
+          *link = thread.timer.take().unwrap().next;
+          return;
+        }
+        link = &mut (**link).timer.as_mut().unwrap().next;
+      }
+    }
+  }
+
+  // Takes back every timed-out thread from wherever it is parked and puts it
+  // on the run queue. Returns the earliest deadline of the remaining timers.
+  fn fire_timers(&mut self) -> Option<Instant<U>> {
+    if self.timers.is_null() {
+      return None;
+    }
+    let now = U::C::now();
+    let mut next = None;
+    unsafe {
+      let mut link: *mut *mut Thread<U> = &mut self.timers;
+      while !(*link).is_null() {
+        let thread = *link;
+        let (deadline, cancel, after) = {
+          let timer = (*thread).timer.as_ref().unwrap();
+          (timer.deadline, timer.cancel, timer.next)
+        };
+        if deadline <= now {
+          // `None` means it was woken up and its Schedule request is on the way.
+          if let Some(node) = cancel(&*thread) {
+            debug!("timing out {}", (*thread).id);
+            (*thread).timer = None;
+            (*thread).timed_out = true;
+            self.released(&mut *thread);
+            *link = after;
+            self.queue.push(node);
+            continue;
+          }
+        } else {
+          next = earliest(next, Some(deadline));
+        }
+        link = &mut (*thread).timer.as_mut().unwrap().next;
+      }
+    }
+    next
   }
   
+  // Runs the front thread until it makes a request, or returns `None` if it
+  // finished or was killed.
   fn next_request(&mut self, response: Response<U>) -> Option<Request<U>> {
-    let front: Option<&mut U::N> = self.queue.front_mut();
-    front.map(|x| {
-      debug!("front is 0x{:x}", x.deref_mut() as *const Thread<U> as usize);
-      let r = x.deref_mut().resume(response).unwrap_or(Request::Unschedule(None));
-      debug!("back");
-      r
-    })
+    let since = U::C::now();
+    let r = {
+      let front = self.queue.front_mut().unwrap();
+      debug!("front is {}", front.deref().id);
+      if front.deref().overflowed {
+        // Killed while parked, and woken up since.
+        return None;
+      }
+      front.deref_mut().resume(response)
+    };
+    debug!("back");
+    self.queue.ran(since);
+    r
+  }
+
+  // Finishes a thread that overflowed its stack, wherever its last request
+  // left it. One parked on a wait queue can't be reached from here, so it is
+  // finished once woken up instead.
+  fn kill(&mut self, thread: *const Thread<U>, response: Response<U>) {
+    debug!("killing thread {}", unsafe { (*thread).id });
+    let node = match response {
+      // It unscheduled itself and would have got its own node back.
+      Response::Unscheduled(Some(node)) => Some(node),
+      _ => {
+        let thread = unsafe { &mut *(thread as *mut Thread<U>) };
+        if let Some(node) = self.queue.remove(thread) {
+          Some(node)
+        } else if let Some(mut node) = self.sleepers.remove(thread) {
+          node.deref_mut().wake_at = None;
+          Some(node)
+        } else if let Some(cancel) = thread.timer.as_ref().map(|timer| timer.cancel) {
+          // Parked with a timeout, so it can be taken back.
+          self.disarm_timer(thread);
+          cancel(thread).map(|mut node| {
+            self.released(node.deref_mut());
+            node
+          })
+        } else {
+          None
+        }
+      }
+    };
+    if let Some(node) = node {
+      node.into_thread().finished();
+    }
   }
 
   pub fn run(&mut self) {
     debug!("=====Scheduler start=====");
     let mut response = Response::Nothing;
     
-    while let Some(request) = self.next_request(response) {
+    loop {
+        if self.cpu().stop.load(Ordering::SeqCst) {
+          break;
+        }
+        self.drain_deferred();
+        self.drain_inbox();
+        self.answer_thief();
+        let next_wakeup = earliest(self.wake_sleepers(), self.fire_timers());
+        if self.queue.front().is_none() {
+          if self.try_steal() {
+            continue;
+          }
+          if let Some(idle) = self.idle {
+            idle.idle();
+            self.refused = 0;
+            continue;
+          }
+          if self.cpus().len() > 1 && self.parked > 0 {
+            // Another CPU may wake a thread up at any moment, so rather than
+            // wait on the clock, wait for it to be handed over, checking
+            // the clock now and then, and keep asking peers for work.
+            self.refused = 0;
+            self.cpu().wait();
+            continue;
+          }
+          match next_wakeup {
+            Some(deadline) => {
+              debug!("all threads asleep, waiting");
+              U::C::wait_until(deadline);
+              continue;
+            }
+            None => break,
+          }
+        }
+        let request = match self.next_request(response) {
+          Some(request) => request,
+          None => {
+            self.queue.pop().unwrap().into_thread().finished();
+            response = Response::Nothing;
+            continue;
+          }
+        };
+        let dying = match self.queue.front() {
+          Some(node) if node.deref().overflowed => node.deref() as *const Thread<U>,
+          _ => ptr::null(),
+        };
         response = match request {
           Request::Yield => {
               debug!("got yield request");
               let c = self.queue.pop().unwrap();
-              debug!("c is 0x{:x}", c.deref() as *const Thread<U> as usize);
+              debug!("yielding {}", c.deref().id);
               self.queue.push(c);
               Response::Nothing
           },
@@ -152,6 +1329,8 @@ impl<U: SchedulerUnit> Scheduler<U> {
             let node = self.queue.pop().unwrap();
             match maybe_taker {
               Some(ref taker) => {
+                let mut node = node;
+                self.park(&mut node);
                 taker(node);
                 Response::Unscheduled(None)
               }
@@ -160,12 +1339,166 @@ impl<U: SchedulerUnit> Scheduler<U> {
           },
           Request::Schedule(tcb_node) => {
             debug!("got schedule request");
-              self.queue.push(tcb_node);
+              self.schedule(tcb_node);
               Response::Nothing
           },
+          Request::Sleep(deadline) => {
+            debug!("got sleep request");
+            let mut node = self.queue.pop().unwrap();
+            node.deref_mut().wake_at = Some(deadline);
+            self.sleepers.push(node);
+            Response::Nothing
+          },
+          Request::UnscheduleUntil(deadline, taker, cancel) => {
+            debug!("got timed unschedule request");
+            let mut node = self.queue.pop().unwrap();
+            self.arm_timer(node.deref_mut(), deadline, cancel);
+            self.park(&mut node);
+            taker(node);
+            Response::Unscheduled(None)
+          },
+          Request::Shutdown => {
+            debug!("got shutdown request");
+            break;
+          },
+          Request::Inspect(f) => {
+            debug!("got inspect request");
+            self.visit(true, f);
+            Response::Nothing
+          },
+          Request::Requeue(ThreadRef(thread)) => {
+            debug!("got requeue request");
+            // The thread may have finished on another CPU since, so it is
+            // only followed once found in our queue.
+            let mut queued = false;
+            self.queue.for_each(&mut |t| queued = queued || t as *const Thread<U> == thread);
+            if queued {
+              if let Some(node) = self.queue.remove(unsafe { &*thread }) {
+                self.queue.push(node);
+              }
+            }
+            Response::Nothing
+          },
+        };
+        if !dying.is_null() {
+          self.kill(dying, response);
+          response = Response::Nothing;
         }
     }
+    self.cpu().finished.store(true, Ordering::SeqCst);
     debug!("=====Scheduler end=====");
   }
 
 }
+
+fn never<U: SchedulerUnit>(_: &Thread<U>, _: usize) -> bool {
+  false
+}
+
+#[cfg(any(test, feature = "hosted"))]
+pub use self::condvar_idle::CondvarIdle;
+
+#[cfg(any(test, feature = "hosted"))]
+mod condvar_idle {
+
+  use std::sync::{Condvar, Mutex};
+  use std::time::Duration;
+
+  use super::Idle;
+
+  /// Blocks the OS thread running the scheduler until it is handed a
+  /// thread, or `timeout` passes so that sleepers and timers get checked.
+  pub struct CondvarIdle {
+    woken: Mutex<bool>,
+    condvar: Condvar,
+    timeout: Duration,
+  }
+
+  impl CondvarIdle {
+
+    pub fn new(timeout: Duration) -> CondvarIdle {
+      CondvarIdle { woken: Mutex::new(false), condvar: Condvar::new(), timeout: timeout }
+    }
+
+  }
+
+  impl Idle for CondvarIdle {
+
+    fn idle(&self) {
+      let mut woken = self.woken.lock().unwrap();
+      if !*woken {
+        woken = self.condvar.wait_timeout(woken, self.timeout).unwrap().0;
+      }
+      *woken = false;
+    }
+
+    fn wake(&self) {
+      *self.woken.lock().unwrap() = true;
+      self.condvar.notify_one();
+    }
+
+  }
+
+}
+
+/// Runs each queue on its own scheduler and OS thread, sharing locks between
+/// them, and returns once every scheduler has finished.
+#[cfg(feature = "hosted")]
+pub fn run_smp<U: SchedulerUnit>(queues: ::std::vec::Vec<U::Q>) where U::Q: Send {
+  smp::run::<U>(queues, smp::unbalanced)
+}
+
+/// Like `run_smp`, but idle schedulers steal threads from busy ones.
+#[cfg(feature = "hosted")]
+pub fn run_smp_balanced<U: SchedulerUnit>(queues: ::std::vec::Vec<U::Q>)
+  where U::Q: Send, U::L: Affinity {
+  smp::run::<U>(queues, Scheduler::balanced)
+}
+
+#[cfg(feature = "hosted")]
+mod smp {
+
+  use std::boxed::Box;
+  use std::thread;
+  use std::vec::Vec;
+
+  use super::{Cpu, Scheduler, SchedulerUnit};
+
+  pub fn unbalanced<U: SchedulerUnit>(scheduler: Scheduler<U>) -> Scheduler<U> {
+    scheduler
+  }
+
+  // The CPUs shared by the schedulers, freed once all of them are done,
+  // even if one of them panicked.
+  struct Running<U: SchedulerUnit> {
+    cpus: &'static [Cpu<U>],
+    handles: Vec<thread::JoinHandle<()>>,
+  }
+
+  impl<U: SchedulerUnit> Drop for Running<U> {
+
+    fn drop(&mut self) {
+      for handle in self.handles.drain(..) {
+        let _ = handle.join();
+      }
+      unsafe { Box::from_raw(self.cpus as *const [Cpu<U>] as *mut [Cpu<U>]) };
+    }
+
+  }
+
+  pub fn run<U: SchedulerUnit>(queues: Vec<U::Q>, setup: fn(Scheduler<U>) -> Scheduler<U>)
+    where U::Q: Send {
+    let cpus: Vec<Cpu<U>> = queues.iter().map(|_| Cpu::new()).collect();
+    let cpus: &'static [Cpu<U>] = unsafe { &*Box::into_raw(cpus.into_boxed_slice()) };
+    let mut running = Running { cpus: cpus, handles: Vec::new() };
+    for (id, queue) in queues.into_iter().enumerate() {
+      running.handles.push(thread::spawn(move || setup(Scheduler::new_smp(queue, cpus, id)).run()));
+    }
+    let results: Vec<_> = running.handles.drain(..).map(|handle| handle.join()).collect();
+    drop(running);
+    for result in results {
+      result.unwrap();
+    }
+  }
+
+}
@@ -0,0 +1,175 @@
+// Waiting on several sources at once.
+//
+// A thread only has one node, so it can't sit on several `U::Q` wait queues.
+// Instead `select` parks the node in a `Waker` and registers that waker with
+// each source. Whichever source becomes ready first takes the node back out
+// and schedules it; the waker is then unregistered from the others.
+//
+// Channel receivers and the lock types (`Mutex`, `Semaphore`, `Condvar`) can
+// be selected on. The `select!` macro picks a source and runs its arm.
+
+extern crate alloc;
+
+use self::alloc::arc::Arc;
+
+use linked_list::LinkedList;
+use preempt::no_preempt;
+use scheduler::{Thread, SchedulerUnit, Queue, BlockedOn};
+
+/// A proxy waiter standing in for a selecting thread on several sources.
+pub struct Waker<U: SchedulerUnit> {
+  state: ::spin_lock::SpinLock<WakerState<U>>,
+}
+
+struct WakerState<U: SchedulerUnit> {
+  // Index of the first source to fire.
+  fired: Option<usize>,
+  waiter: Option<U::N>,
+}
+
+unsafe impl<U: SchedulerUnit> Send for Waker<U> {}
+unsafe impl<U: SchedulerUnit> Sync for Waker<U> {}
+
+impl<U: SchedulerUnit> Waker<U> {
+
+  fn new() -> Waker<U> {
+    Waker { state: ::spin_lock::SpinLock::new(WakerState { fired: None, waiter: None }) }
+  }
+
+  // Returns the parked thread, if this is the first source to fire. The
+  // caller must schedule it after dropping its own locks.
+  fn fire(&self, index: usize) -> Option<U::N> {
+    let mut state = self.state.lock();
+    if state.fired.is_none() {
+      state.fired = Some(index);
+    }
+    state.waiter.take()
+  }
+
+}
+
+/// The wakers registered with one source.
+pub struct Watchers<U: SchedulerUnit> {
+  list: LinkedList<(Arc<Waker<U>>, usize)>,
+}
+
+impl<U: SchedulerUnit> Watchers<U> {
+
+  pub fn new() -> Watchers<U> {
+    Watchers { list: LinkedList::new() }
+  }
+
+  pub fn add(&mut self, waker: &Arc<Waker<U>>, index: usize) {
+    self.list.push_back((waker.clone(), index));
+  }
+
+  pub fn remove(&mut self, waker: &Arc<Waker<U>>) {
+    let waker: *const Waker<U> = &**waker;
+    self.list.remove_node_where(|&(ref w, _)| &**w as *const Waker<U> == waker);
+  }
+
+  /// Fires every registered waker, moving the threads to wake onto `woken`.
+  pub fn fire_all(&mut self, woken: &mut U::Q) {
+    while let Some((waker, index)) = self.list.pop_front() {
+      if let Some(node) = waker.fire(index) {
+        woken.push(node);
+      }
+    }
+  }
+
+}
+
+/// Something `select` can wait on.
+pub trait Selectable<U: SchedulerUnit> {
+
+  /// Returns true if waiting on the source wouldn't block. Otherwise
+  /// registers `waker` to be fired with `index` once that changes.
+  fn register(&self, waker: &Arc<Waker<U>>, index: usize) -> bool;
+
+  fn unregister(&self, waker: &Arc<Waker<U>>);
+
+}
+
+impl<'a, U: SchedulerUnit, S: Selectable<U> + ?Sized> Selectable<U> for &'a S {
+
+  fn register(&self, waker: &Arc<Waker<U>>, index: usize) -> bool {
+    (**self).register(waker, index)
+  }
+
+  fn unregister(&self, waker: &Arc<Waker<U>>) {
+    (**self).unregister(waker)
+  }
+
+}
+
+/// Waits until one of `sources` is ready and returns its index.
+///
+/// Readiness is only guaranteed to last for sources with a single consumer,
+/// like channel receivers.
+pub fn select<U: SchedulerUnit>(sources: &[&Selectable<U>]) -> usize {
+  assert!(!sources.is_empty(), "select needs at least one source");
+  let waker = no_preempt(|| Arc::new(Waker::new()));
+  let mut registered = 0;
+  let mut ready = None;
+  for (i, source) in sources.iter().enumerate() {
+    if source.register(&waker, i) {
+      ready = Some(i);
+      break;
+    }
+    registered += 1;
+  }
+  if ready.is_none() {
+    let mut state = waker.state.lock();
+    ready = state.fired;
+    if ready.is_none() {
+      debug!("no source ready, sleeping");
+      let take = move |me| {
+        state.waiter = Some(me);
+        drop(state);
+      };
+      Thread::<U>::park(BlockedOn::new("select", &*waker), &take);
+      ready = waker.state.lock().fired;
+    }
+  }
+  for source in &sources[..registered] {
+    source.unregister(&waker);
+  }
+  no_preempt(move || drop(waker));
+  ready.unwrap()
+}
+
+/// Waits on several sources, then runs the arm of the one that is ready:
+///
+/// ```ignore
+/// select! {
+///   msg = rx.recv() => handle(msg),
+///   guard = mutex.lock() => update(guard),
+/// }
+/// ```
+///
+/// Each source is named by an identifier, and its method is only called
+/// once it has been picked. The same caveat as for `select` applies: a
+/// mutex or semaphore that was free may have been taken again by then, in
+/// which case the call blocks.
+#[macro_export]
+macro_rules! select {
+  (@arm $index:ident, $i:expr; $name:pat = $source:ident.$method:ident() => $code:expr) => {{
+    debug_assert_eq!($index, $i);
+    let $name = $source.$method();
+    $code
+  }};
+  (@arm $index:ident, $i:expr; $name:pat = $source:ident.$method:ident() => $code:expr,
+   $($rest:tt)+) => {
+    if $index == $i {
+      let $name = $source.$method();
+      $code
+    } else {
+      select!(@arm $index, $i + 1; $($rest)+)
+    }
+  };
+  // Comes last: trying to parse `@arm` as a pattern would be an error.
+  ($($name:pat = $source:ident.$method:ident() => $code:expr),+ $(,)*) => {{
+    let index = $crate::select::select(&[$(&$source as &$crate::select::Selectable<_>),+]);
+    select!(@arm index, 0; $($name = $source.$method() => $code),+)
+  }};
+}
@@ -0,0 +1,71 @@
+// The spin lock everything in barn that threads share is behind.
+//
+// A thread holding one must not be preempted: the next thread to take the
+// lock on the same CPU would spin forever. So taking it keeps the CPU from
+// preempting until it is let go, which may happen in the scheduler rather
+// than the thread, when the guard went with a thread's node as it parked.
+
+use core::ops::{Deref, DerefMut};
+
+use arch;
+
+pub struct SpinLock<T> {
+  inner: ::spin::Mutex<T>,
+}
+
+pub struct SpinLockGuard<'a, T: 'a> {
+  // Let go of before preemption is allowed again.
+  inner: ::spin::MutexGuard<'a, T>,
+  _preempt: Preempt,
+}
+
+struct Preempt;
+
+impl Drop for Preempt {
+
+  fn drop(&mut self) {
+    arch::preempt_enable();
+  }
+
+}
+
+impl<T> SpinLock<T> {
+
+  pub const fn new(value: T) -> SpinLock<T> {
+    SpinLock { inner: ::spin::Mutex::new(value) }
+  }
+
+  pub fn lock(&self) -> SpinLockGuard<T> {
+    arch::preempt_disable();
+    SpinLockGuard { inner: self.inner.lock(), _preempt: Preempt }
+  }
+
+  pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+    arch::preempt_disable();
+    match self.inner.try_lock() {
+      Some(inner) => Some(SpinLockGuard { inner: inner, _preempt: Preempt }),
+      None => {
+        arch::preempt_enable();
+        None
+      }
+    }
+  }
+
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    &*self.inner
+  }
+
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+
+  fn deref_mut(&mut self) -> &mut T {
+    &mut *self.inner
+  }
+
+}
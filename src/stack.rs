@@ -0,0 +1,407 @@
+// Allocating thread stacks, keeping finished threads' stacks around for new
+// ones, and catching threads that overrun their stack.
+//
+// Every stack gets canary words written at its limit, which the scheduler
+// checks each time a thread switches out. That catches most overflows after
+// the fact, anywhere. In hosted builds `ProtectedStack` adds a guard page
+// below the limit, so an overflow faults right away and `catch_overflows`
+// can say which thread it was.
+//
+// Threads created with `Thread::new_painted` (or a `Builder` told to paint)
+// have the rest of the stack painted with a pattern, so how much of it the
+// thread has used can be found later by looking for the lowest word that
+// isn't paint any more. That touches every page, so it is opt-in.
+
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use fringe::Stack;
+
+use scheduler::ThreadId;
+
+/// Stacks that can be allocated at a given size.
+pub trait NewStack: Stack + Sized {
+
+  fn with_size(size: usize) -> Self;
+
+}
+
+#[cfg(feature = "alloc")]
+pub use self::pool::StackPool;
+
+// Stacks on the heap, and pools of them.
+#[cfg(feature = "alloc")]
+mod pool {
+
+  use fringe::OwnedStack;
+
+  use linked_list::LinkedList;
+
+  use super::NewStack;
+
+  impl NewStack for OwnedStack {
+
+    fn with_size(size: usize) -> OwnedStack {
+      OwnedStack::new(size)
+    }
+
+  }
+
+  /// A pool of same-sized stacks.
+  ///
+  /// Threads built with a pool give their stack back to it when they finish,
+  /// so spawning many short threads doesn't allocate a stack for each.
+  pub struct StackPool<S> {
+    size: usize,
+    // Most stacks to keep around; any more are freed.
+    limit: usize,
+    stacks: ::spin_lock::SpinLock<LinkedList<S>>,
+    allocated: ::spin_lock::SpinLock<usize>,
+  }
+
+  impl<S: NewStack> StackPool<S> {
+
+    pub fn new(size: usize, limit: usize) -> StackPool<S> {
+      StackPool {
+        size: size,
+        limit: limit,
+        stacks: ::spin_lock::SpinLock::new(LinkedList::new()),
+        allocated: ::spin_lock::SpinLock::new(0),
+      }
+    }
+
+    /// Takes a stack from the pool, allocating one if it is empty.
+    pub fn get(&self) -> S {
+      let stack = self.stacks.lock().pop_front();
+      match stack {
+        Some(stack) => stack,
+        None => {
+          *self.allocated.lock() += 1;
+          ::preempt::no_preempt(|| S::with_size(self.size))
+        }
+      }
+    }
+
+  }
+
+  impl<S> StackPool<S> {
+
+    pub fn put(&self, stack: S) {
+      let mut stacks = self.stacks.lock();
+      if stacks.len() < self.limit {
+        stacks.push_back(stack);
+      } else {
+        drop(stacks);
+        ::preempt::no_preempt(move || drop(stack));
+        *self.allocated.lock() -= 1;
+      }
+    }
+
+    pub fn size(&self) -> usize {
+      self.size
+    }
+
+    /// Number of stacks waiting in the pool.
+    pub fn len(&self) -> usize {
+      self.stacks.lock().len()
+    }
+
+    /// Number of stacks allocated by the pool and not freed since.
+    pub fn allocated(&self) -> usize {
+      *self.allocated.lock()
+    }
+
+  }
+
+  unsafe impl<S: Send> Send for StackPool<S> {}
+  unsafe impl<S: Send> Sync for StackPool<S> {}
+
+}
+
+const CANARY: usize = 0x57ac_ca4a;
+const CANARY_WORDS: usize = 4;
+const PAINT: usize = 0xa5a5_a5a5;
+
+// Writes the canary words at the bottom of a stack with the given limit,
+// returning where they went.
+pub fn write_canary(limit: *mut u8) -> *mut usize {
+  let align = ::core::mem::align_of::<usize>();
+  let canary = ((limit as usize + align - 1) & !(align - 1)) as *mut usize;
+  for i in 0..CANARY_WORDS {
+    unsafe { ptr::write_volatile(canary.offset(i as isize), CANARY) };
+  }
+  canary
+}
+
+// Fills the stack between the canary words and `base` with paint.
+pub fn paint(canary: *mut usize, base: *mut u8) {
+  let mut word = unsafe { canary.offset(CANARY_WORDS as isize) };
+  while word as usize + size_of::<usize>() <= base as usize {
+    unsafe {
+      ptr::write_volatile(word, PAINT);
+      word = word.offset(1);
+    }
+  }
+}
+
+// The most of a painted stack that has been used: from the lowest word
+// that isn't paint any more up to `base`.
+pub fn high_water(canary: *const usize, base: *const u8) -> usize {
+  let mut word = unsafe { canary.offset(CANARY_WORDS as isize) };
+  while word as usize + size_of::<usize>() <= base as usize
+        && unsafe { ptr::read_volatile(word) } == PAINT {
+    word = unsafe { word.offset(1) };
+  }
+  base as usize - word as usize
+}
+
+/// How much of a thread's stack it has used, from `Thread::stack_usage`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StackUsage {
+  /// Usable bytes, between the stack's base and limit.
+  pub size: usize,
+  /// The most bytes the thread has used at once so far, if its stack was
+  /// painted (see `Thread::new_painted`). A little may be missed if the
+  /// thread happened to leave paint-coloured words behind.
+  pub used: Option<usize>,
+}
+
+pub fn canary_intact(canary: *const usize) -> bool {
+  (0..CANARY_WORDS).all(|i| unsafe { ptr::read_volatile(canary.offset(i as isize)) } == CANARY)
+}
+
+/// What the scheduler does with a thread whose stack canary was overwritten.
+#[derive(Clone, Copy)]
+pub enum OverflowPolicy {
+  /// Panic in the scheduler. The default.
+  Panic,
+  /// Drop the thread without running it again. Its stack is leaked, since
+  /// whatever overran it may still point into it, and anyone joining it or
+  /// waiting on a lock it holds waits forever.
+  Kill,
+  /// Call the function with the thread's id and name, then kill it.
+  Callback(fn(ThreadId, Option<&'static str>)),
+}
+
+// 0 for `Panic`, 1 for `Kill`, or the callback.
+static POLICY: AtomicUsize = ATOMIC_USIZE_INIT;
+
+pub fn overflow_policy() -> OverflowPolicy {
+  match POLICY.load(Ordering::SeqCst) {
+    0 => OverflowPolicy::Panic,
+    1 => OverflowPolicy::Kill,
+    f => OverflowPolicy::Callback(unsafe { ::core::mem::transmute(f) }),
+  }
+}
+
+pub fn set_overflow_policy(policy: OverflowPolicy) {
+  POLICY.store(match policy {
+    OverflowPolicy::Panic => 0,
+    OverflowPolicy::Kill => 1,
+    OverflowPolicy::Callback(f) => f as usize,
+  }, Ordering::SeqCst);
+}
+
+#[cfg(feature = "hosted")]
+pub use self::guard::{ProtectedStack, catch_overflows};
+
+#[cfg(feature = "hosted")]
+mod guard {
+
+  use std::mem;
+  use std::ptr;
+  use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+
+  use fringe::Stack;
+  use libc;
+
+  use scheduler::{Thread, SchedulerUnit, ThreadId};
+  use super::NewStack;
+
+  type Arch<U> = ::arch::Arch<Thread<U>>;
+
+  fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+  }
+
+  /// A stack with an inaccessible guard page below it, so that running off
+  /// the end faults instead of scribbling over whatever is next in memory.
+  pub struct ProtectedStack {
+    ptr: *mut u8,
+    // Including the guard page.
+    len: usize,
+  }
+
+  unsafe impl Send for ProtectedStack {}
+
+  impl ProtectedStack {
+
+    /// Maps at least `size` bytes of stack, plus the guard page.
+    pub fn new(size: usize) -> ProtectedStack {
+      let page = page_size();
+      let len = (size + page - 1) / page * page + page;
+      unsafe {
+        let ptr = libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE,
+                             libc::MAP_PRIVATE | libc::MAP_ANON, -1, 0);
+        assert!(ptr != libc::MAP_FAILED, "couldn't map a stack");
+        assert!(libc::mprotect(ptr, page, libc::PROT_NONE) == 0, "couldn't protect a guard page");
+        ProtectedStack { ptr: ptr as *mut u8, len: len }
+      }
+    }
+
+  }
+
+  impl Stack for ProtectedStack {
+
+    fn base(&self) -> *mut u8 {
+      unsafe { self.ptr.offset(self.len as isize) }
+    }
+
+    fn limit(&self) -> *mut u8 {
+      unsafe { self.ptr.offset(page_size() as isize) }
+    }
+
+  }
+
+  impl NewStack for ProtectedStack {
+
+    fn with_size(size: usize) -> ProtectedStack {
+      ProtectedStack::new(size)
+    }
+
+  }
+
+  impl Drop for ProtectedStack {
+
+    fn drop(&mut self) {
+      unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+
+  }
+
+  // What the kernel passes to a SIGSEGV handler, up to the faulting address
+  // (libc only gives the padding).
+  #[repr(C)]
+  struct SigInfo {
+    si_signo: libc::c_int,
+    si_errno: libc::c_int,
+    si_code: libc::c_int,
+    si_addr: usize,
+  }
+
+  static INSTALLED: AtomicBool = ATOMIC_BOOL_INIT;
+  // The `check::<U>` instance the handler calls, as a `fn(usize)`.
+  static CHECK: AtomicUsize = ATOMIC_USIZE_INIT;
+  static mut PREVIOUS: Option<libc::sigaction> = None;
+
+  /// Makes a fault in the guard page of the running thread's
+  /// `ProtectedStack` print which thread overflowed, then abort. Any other
+  /// fault goes to the handler that was installed before.
+  ///
+  /// Must be called on each OS thread that runs a scheduler, before it does
+  /// (the handler needs a stack of its own to run on).
+  pub fn catch_overflows<U: SchedulerUnit>() {
+    CHECK.store(check::<U> as fn(usize) as usize, Ordering::SeqCst);
+    unsafe {
+      let mut current: libc::stack_t = mem::zeroed();
+      libc::sigaltstack(ptr::null(), &mut current);
+      if current.ss_flags & libc::SS_DISABLE != 0 {
+        // Leaked: the OS thread may use it until it exits.
+        let size = libc::SIGSTKSZ;
+        let stack = libc::mmap(ptr::null_mut(), size, libc::PROT_READ | libc::PROT_WRITE,
+                               libc::MAP_PRIVATE | libc::MAP_ANON, -1, 0);
+        assert!(stack != libc::MAP_FAILED, "couldn't map a signal stack");
+        let alt = libc::stack_t { ss_sp: stack, ss_flags: 0, ss_size: size };
+        libc::sigaltstack(&alt, ptr::null_mut());
+      }
+      if INSTALLED.swap(true, Ordering::SeqCst) {
+        return;
+      }
+      let mut action: libc::sigaction = mem::zeroed();
+      action.sa_sigaction = on_fault as libc::sighandler_t;
+      action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+      libc::sigemptyset(&mut action.sa_mask);
+      let mut previous: libc::sigaction = mem::zeroed();
+      libc::sigaction(libc::SIGSEGV, &action, &mut previous);
+      PREVIOUS = Some(previous);
+    }
+  }
+
+  extern "C" fn on_fault(signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+    let addr = unsafe { (*(info as *const SigInfo)).si_addr };
+    let check = CHECK.load(Ordering::SeqCst);
+    if check != 0 {
+      let check: fn(usize) = unsafe { mem::transmute(check) };
+      check(addr);
+    }
+    // Not ours: hand it on to the previous handler, staying installed.
+    unsafe {
+      let previous = match PREVIOUS {
+        Some(ref previous) => previous,
+        None => return,
+      };
+      match previous.sa_sigaction {
+        libc::SIG_IGN => {}
+        libc::SIG_DFL => {
+          // The default is to die, which needs the fault to happen again
+          // without us in the way.
+          libc::signal(signal, libc::SIG_DFL);
+        }
+        handler if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+          let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+            mem::transmute(handler);
+          handler(signal, info, context);
+        }
+        handler => {
+          let handler: extern "C" fn(libc::c_int) = mem::transmute(handler);
+          handler(signal);
+        }
+      }
+    }
+  }
+
+  // Aborts if `addr` is in the guard page below the running thread's stack.
+  fn check<U: SchedulerUnit>(addr: usize) {
+    // Not while the scheduler is running: its stack is the OS thread's.
+    if !Arch::<U>::in_thread() {
+      return;
+    }
+    let thread = Thread::<U>::current();
+    let limit = thread.stack_limit();
+    if addr >= limit || addr < limit - page_size() {
+      return;
+    }
+    report(thread.id(), thread.name());
+    unsafe { libc::abort() };
+  }
+
+  // No allocating or formatting in a signal handler, so the message is put
+  // together by hand.
+  fn report(id: ThreadId, name: Option<&'static str>) {
+    let mut digits = [0u8; 20];
+    let mut n = id.as_usize();
+    let mut start = digits.len();
+    loop {
+      start -= 1;
+      digits[start] = b'0' + (n % 10) as u8;
+      n /= 10;
+      if n == 0 {
+        break;
+      }
+    }
+    write(b"thread #");
+    write(&digits[start..]);
+    if let Some(name) = name {
+      write(b" '");
+      write(name.as_bytes());
+      write(b"'");
+    }
+    write(b" overflowed its stack\n");
+  }
+
+  fn write(bytes: &[u8]) {
+    unsafe { libc::write(libc::STDERR_FILENO, bytes.as_ptr() as *const libc::c_void, bytes.len()) };
+  }
+
+}
@@ -0,0 +1,182 @@
+// Spawning threads that hand a value back to whoever joins them.
+
+extern crate alloc;
+
+use self::alloc::arc::Arc;
+
+use preempt::no_preempt;
+use scheduler::{Thread, Request, SchedulerUnit, Node, BlockedOn};
+use stack::{NewStack, StackPool};
+
+/// Stack size `Builder` uses unless told otherwise.
+pub const DEFAULT_STACK_SIZE: usize = 1024 * 1024;
+
+struct Packet<T, U: SchedulerUnit> {
+  state: ::spin_lock::SpinLock<PacketState<T, U>>,
+}
+
+struct PacketState<T, U: SchedulerUnit> {
+  // Still `None` once done if the thread panicked.
+  result: Option<T>,
+  done: bool,
+  joiner: Option<U::N>,
+}
+
+/// An owned permission to wait for a thread to finish and take its result.
+pub struct JoinHandle<T, U: SchedulerUnit> {
+  packet: Arc<Packet<T, U>>,
+}
+
+/// Creates a thread running `f` along with a handle to join it.
+///
+/// The thread is not scheduled; push it onto a queue (or use `spawn` from
+/// inside a running thread).
+pub fn new<U, F, T>(stack: U::S, f: F) -> (Thread<U>, JoinHandle<T, U>)
+  where U: SchedulerUnit, F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+  packaged(stack, false, f)
+}
+
+fn packaged<U, F, T>(stack: U::S, paint: bool, f: F) -> (Thread<U>, JoinHandle<T, U>)
+  where U: SchedulerUnit, F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+  let packet = no_preempt(|| Arc::new(Packet {
+    state: ::spin_lock::SpinLock::new(PacketState { result: None, done: false, joiner: None }),
+  }));
+  let their_packet = packet.clone();
+  let body = move || {
+    let result = run(f);
+    let joiner = {
+      let mut state = their_packet.state.lock();
+      state.result = result;
+      state.done = true;
+      state.joiner.take()
+    };
+    // The joiner may be gone already, leaving the packet to us to free.
+    no_preempt(move || drop(their_packet));
+    if let Some(node) = joiner {
+      debug!("waking joiner");
+      Thread::<U>::suspend(Request::Schedule(node));
+    }
+  };
+  let thread = if paint { Thread::new_painted(stack, body) } else { Thread::new(stack, body) };
+  (thread, JoinHandle { packet: packet })
+}
+
+/// Spawns `f` on a new thread from inside a running thread.
+pub fn spawn<U, F, T>(stack: U::S, f: F) -> JoinHandle<T, U>
+  where U: SchedulerUnit, F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+  let (thread, handle) = new(stack, f);
+  let node = no_preempt(|| U::N::new(thread));
+  Thread::<U>::suspend(Request::Schedule(node));
+  handle
+}
+
+/// Configures a new thread: its stack and name.
+pub struct Builder<U: SchedulerUnit> {
+  stack_size: usize,
+  name: Option<&'static str>,
+  pool: Option<Arc<StackPool<U::S>>>,
+  paint: bool,
+}
+
+impl<U: SchedulerUnit> Builder<U> where U::S: NewStack {
+
+  pub fn new() -> Builder<U> {
+    Builder { stack_size: DEFAULT_STACK_SIZE, name: None, pool: None, paint: false }
+  }
+
+  pub fn stack_size(mut self, size: usize) -> Builder<U> {
+    self.stack_size = size;
+    self
+  }
+
+  pub fn name(mut self, name: &'static str) -> Builder<U> {
+    self.name = Some(name);
+    self
+  }
+
+  /// Takes the stack from `pool`, and gives it back when the thread
+  /// finishes. The pool's stack size overrides `stack_size`.
+  pub fn stack_pool(mut self, pool: Arc<StackPool<U::S>>) -> Builder<U> {
+    self.pool = Some(pool);
+    self
+  }
+
+  /// Paints the stack so that its high-water mark can be measured, see
+  /// `Thread::new_painted`. Off by default.
+  pub fn paint_stack(mut self, paint: bool) -> Builder<U> {
+    self.paint = paint;
+    self
+  }
+
+  /// Creates the thread without scheduling it, like `new`.
+  pub fn build<F, T>(self, f: F) -> (Thread<U>, JoinHandle<T, U>)
+    where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    let stack = match self.pool {
+      Some(ref pool) => pool.get(),
+      None => no_preempt(|| U::S::with_size(self.stack_size)),
+    };
+    let (mut thread, handle) = packaged(stack, self.paint, f);
+    if let Some(name) = self.name {
+      thread.set_name(name);
+    }
+    if let Some(pool) = self.pool {
+      thread.set_stack_pool(pool);
+    }
+    (thread, handle)
+  }
+
+  /// Spawns the thread from inside a running thread, like `spawn`.
+  pub fn spawn<F, T>(self, f: F) -> JoinHandle<T, U>
+    where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    let (thread, handle) = self.build(f);
+    let node = no_preempt(|| U::N::new(thread));
+    Thread::<U>::suspend(Request::Schedule(node));
+    handle
+  }
+
+}
+
+impl<T, U: SchedulerUnit> JoinHandle<T, U> {
+
+  /// Waits for the thread to finish and returns its result.
+  ///
+  /// Must be called from inside a running thread if the thread may not
+  /// have finished yet. Panics if the thread panicked.
+  pub fn join(self) -> T {
+    let result = {
+      let mut state = self.packet.state.lock();
+      if !state.done {
+        debug!("thread not done, sleeping until it is");
+        {
+          let take = move |me| {
+            state.joiner = Some(me);
+            drop(state);
+          };
+          Thread::<U>::park(BlockedOn::new("join", &*self.packet), &take);
+        }
+        state = self.packet.state.lock();
+      }
+      state.result.take()
+    };
+    no_preempt(move || drop(self));
+    result.expect("joined thread panicked")
+  }
+
+}
+
+// The panic is caught before the joiner is woken up, so that the joiner
+// finds the result missing. Other threads may still run while this one
+// unwinds (dropping a lock guard can switch), which is fine: whether a
+// thread is panicking is tracked per barn thread.
+#[cfg(feature = "hosted")]
+fn run<F, T>(f: F) -> Option<T> where F: FnOnce() -> T {
+  let result = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(f)).ok();
+  // Done unwinding: locks taken from here on can't be poisoned by it.
+  unsafe { ::arch::Arch::<()>::set_panicking(false) };
+  result
+}
+
+#[cfg(not(feature = "hosted"))]
+fn run<F, T>(f: F) -> Option<T> where F: FnOnce() -> T {
+  Some(f())
+}
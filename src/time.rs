@@ -0,0 +1,172 @@
+// Clocks that the scheduler uses to put threads to sleep.
+
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+pub trait Clock where Self: Sized + 'static {
+  type Instant: Copy + Ord + Send + 'static;
+  type Duration: Copy + Send + 'static;
+
+  fn now() -> Self::Instant;
+
+  fn after(instant: Self::Instant, duration: Self::Duration) -> Self::Instant;
+
+  // Called by the scheduler when no thread can run before `deadline`.
+  // The default just spins until the clock catches up.
+  fn wait_until(deadline: Self::Instant) {
+    while Self::now() < deadline {}
+  }
+}
+
+/// A clock counting timer ticks, for bare-metal targets.
+///
+/// The timer interrupt handler is expected to call `TickClock::tick()`.
+pub struct TickClock;
+
+static TICKS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+impl TickClock {
+
+  pub fn tick() {
+    TICKS.fetch_add(1, Ordering::SeqCst);
+  }
+
+}
+
+impl Clock for TickClock {
+  type Instant = usize;
+  type Duration = usize;
+
+  fn now() -> usize {
+    TICKS.load(Ordering::SeqCst)
+  }
+
+  fn after(instant: usize, duration: usize) -> usize {
+    instant.saturating_add(duration)
+  }
+}
+
+#[cfg(feature = "hosted")]
+pub use self::std_clock::{StdClock, StdTickClock};
+
+#[cfg(feature = "hosted")]
+mod std_clock {
+
+  use std::time::{Duration, Instant};
+  use std::thread;
+  use std::sync::{Once, ONCE_INIT};
+
+  use super::Clock;
+
+  /// Wall-clock time from `std::time::Instant`.
+  pub struct StdClock;
+
+  impl Clock for StdClock {
+    type Instant = Instant;
+    type Duration = Duration;
+
+    fn now() -> Instant {
+      Instant::now()
+    }
+
+    fn after(instant: Instant, duration: Duration) -> Instant {
+      instant + duration
+    }
+
+    fn wait_until(deadline: Instant) {
+      let now = Instant::now();
+      if deadline > now {
+        thread::sleep(deadline - now);
+      }
+    }
+  }
+
+  /// Wall-clock time in ticks of a microsecond, counted from when the clock
+  /// was first read, for units that count in ticks (`edf`, `fair`).
+  pub struct StdTickClock;
+
+  static START: Once = ONCE_INIT;
+  static mut START_AT: Option<Instant> = None;
+
+  fn start() -> Instant {
+    unsafe {
+      START.call_once(|| START_AT = Some(Instant::now()));
+      START_AT.unwrap()
+    }
+  }
+
+  impl Clock for StdTickClock {
+    type Instant = usize;
+    type Duration = usize;
+
+    fn now() -> usize {
+      let elapsed = start().elapsed();
+      (elapsed.as_secs() as usize) * 1_000_000 + (elapsed.subsec_nanos() / 1_000) as usize
+    }
+
+    fn after(instant: usize, duration: usize) -> usize {
+      instant.saturating_add(duration)
+    }
+
+    fn wait_until(deadline: usize) {
+      let now = Self::now();
+      if deadline > now {
+        let micros = (deadline - now) as u64;
+        thread::sleep(Duration::new(micros / 1_000_000, (micros % 1_000_000) as u32 * 1_000));
+      }
+    }
+  }
+
+}
+
+#[cfg(any(test, feature = "hosted"))]
+pub use self::manual_clock::ManualClock;
+
+#[cfg(any(test, feature = "hosted"))]
+mod manual_clock {
+
+  use std::cell::Cell;
+
+  use super::Clock;
+
+  thread_local! {
+    static NOW: Cell<usize> = Cell::new(0);
+  }
+
+  /// A virtual clock that only moves when told to, for deterministic tests.
+  ///
+  /// Time is kept per OS thread. When every barn thread is asleep the
+  /// scheduler skips straight to the earliest deadline instead of waiting.
+  pub struct ManualClock;
+
+  impl ManualClock {
+
+    pub fn advance(ticks: usize) {
+      NOW.with(|n| n.set(n.get() + ticks));
+    }
+
+    pub fn set(now: usize) {
+      NOW.with(|n| n.set(now));
+    }
+
+  }
+
+  impl Clock for ManualClock {
+    type Instant = usize;
+    type Duration = usize;
+
+    fn now() -> usize {
+      NOW.with(|n| n.get())
+    }
+
+    fn after(instant: usize, duration: usize) -> usize {
+      instant.saturating_add(duration)
+    }
+
+    fn wait_until(deadline: usize) {
+      if deadline > Self::now() {
+        Self::set(deadline);
+      }
+    }
+  }
+
+}